@@ -1,10 +1,36 @@
 use std::sync::Arc;
 
 use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 use serde_repr::*;
 use sha3::{Digest, Sha3_256};
 use tokio::io::AsyncReadExt;
+use uuid::Uuid;
+
+/// 响应头：当前发布的快照版本
+pub const VERSION_HEADER: &str = "x-syner-version";
+
+/// 请求头：客户端 ID
+pub const CLIENT_ID_HEADER: &str = "x-syner-client";
+
+/// 请求头：客户端程序版本
+pub const CLIENT_VERSION_HEADER: &str = "x-syner-client-version";
+
+/// 请求头：客户端能处理的清单格式，没有此请求头的旧客户端不支持 [`ItemOp::RemoveGlob`]
+pub const MANIFEST_FORMAT_HEADER: &str = "x-syner-manifest-format";
+
+/// 当前的清单格式，1 起支持 [`ItemOp::RemoveGlob`]
+pub const MANIFEST_FORMAT: u32 = 1;
+
+/// 请求头：客户端向局域网其他客户端提供文件的端口
+pub const PEER_PORT_HEADER: &str = "x-syner-peer-port";
+
+/// 响应头：客户端之间传输的文件的哈希（小写十六进制）
+pub const HASH_HEADER: &str = "x-syner-hash";
+
+/// 响应头：清单中发布的各平台客户端程序，`<平台>=<长度>:<哈希>` 以 `,` 分隔
+pub const UPDATE_HEADER: &str = "x-syner-update";
 
 pub type Manifest = Arc<DashMap<String, ManifestItem>>;
 
@@ -18,6 +44,135 @@ pub type ManifestItem = (ItemOp, u64, ByteBuf);
 pub enum ItemOp {
     Sync,
     Remove,
+    /// 路径是 glob，由客户端在本地展开，删除匹配的文件（清单中要同步的文件除外）
+    RemoveGlob,
+}
+
+/// 客户端完成同步后，以 msgpack 格式 POST 到 `/report`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncReport {
+    pub client_id: Uuid,
+    pub client_version: String,
+    /// 同步所依据的清单版本
+    pub manifest_version: Option<String>,
+    pub duration_ms: u64,
+    pub bytes: u64,
+    pub items: Vec<SyncReportItem>,
+    /// 同步中途失败时的错误信息
+    pub error: Option<String>,
+}
+
+impl SyncReport {
+    pub fn is_success(&self) -> bool {
+        self.error.is_none() && self.items.iter().all(|a| a.outcome != ItemOutcome::Failed)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncReportItem {
+    pub path: String,
+    pub op: ItemOp,
+    pub outcome: ItemOutcome,
+    pub error: Option<String>,
+}
+
+#[repr(u8)]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize_repr, Deserialize_repr,
+)]
+pub enum ItemOutcome {
+    Updated,
+    Unchanged,
+    Removed,
+    Failed,
+}
+
+/// 服务器为某个平台发布的客户端程序，随清单在 [`UPDATE_HEADER`] 中发布，
+/// 程序本身通过 `/blob/<hash>` 下载
+#[derive(Debug, Clone)]
+pub struct ClientUpdate {
+    pub len: u64,
+    pub hash: ByteBuf,
+}
+
+impl ClientUpdate {
+    /// [`UPDATE_HEADER`] 中的一项
+    pub fn to_header(&self, platform: &str) -> String {
+        let hash: String = self.hash.iter().map(|b| format!("{b:02x}")).collect();
+        format!("{platform}={}:{hash}", self.len)
+    }
+
+    /// 从 [`UPDATE_HEADER`] 中找出指定平台的程序，格式错误的项忽略
+    pub fn from_header(value: &str, platform: &str) -> Option<Self> {
+        value.split(',').find_map(|entry| {
+            let (name, entry) = entry.trim().split_once('=')?;
+            if name != platform {
+                return None;
+            }
+            let (len, hash) = entry.split_once(':')?;
+            if hash.len() != 64 || !hash.is_ascii() {
+                return None;
+            }
+            let hash = (0..hash.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hash[i..i + 2], 16).ok())
+                .collect::<Option<Vec<u8>>>()?;
+            Some(Self {
+                len: len.parse().ok()?,
+                hash: ByteBuf::from(hash),
+            })
+        })
+    }
+}
+
+/// 客户端更新使用的平台名，如 `windows-x86_64`
+pub fn platform() -> String {
+    format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH)
+}
+
+/// 客户端同步、服务器发布前后执行的命令
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HookConfig {
+    /// 开始前执行，第一项为程序，其余为参数，为空时不执行
+    pub before: Vec<String>,
+    /// 结束后执行
+    pub after: Vec<String>,
+    /// 命令失败时取消本次同步或发布，否则只记录错误
+    pub abort_on_failure: bool,
+}
+
+/// 执行钩子命令并等待退出，退出码非 0 时返回错误
+///
+/// 变化的路径逐行写入临时文件，文件路径由 `SYNER_CHANGED_FILE` 传递
+pub async fn run_hook(
+    command: &[String],
+    envs: &[(&str, String)],
+    changed: &[String],
+) -> anyhow::Result<()> {
+    let Some((program, args)) = command.split_first() else {
+        return Ok(());
+    };
+    let stamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let list =
+        std::env::temp_dir().join(format!("syner-changed-{}-{stamp}.txt", std::process::id()));
+    tokio::fs::write(&list, changed.join("\n")).await?;
+    let status = tokio::process::Command::new(program)
+        .args(args)
+        .envs(envs.iter().map(|(k, v)| (k, v)))
+        .env("SYNER_CHANGED_FILE", &list)
+        .env("SYNER_CHANGED_COUNT", changed.len().to_string())
+        .status()
+        .await;
+    let _ = tokio::fs::remove_file(&list).await;
+    let status = status?;
+    if !status.success() {
+        anyhow::bail!("{program} exited with {status}");
+    }
+    Ok(())
 }
 
 pub async fn calc_hash(mut file: tokio::fs::File) -> anyhow::Result<Vec<u8>> {
@@ -0,0 +1,350 @@
+use std::convert::Infallible;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use warp::{
+    http::{header::RETRY_AFTER, StatusCode},
+    reject::{Reject, Rejection},
+    reply::{Reply, Response},
+    Filter,
+};
+
+use crate::client_ip::{get_ip, ClientInfo};
+use crate::server_model::Config;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessConfig {
+    /// 允许访问的网络，为空时允许所有地址
+    pub allow: Vec<IpNet>,
+    /// 拒绝访问的网络，优先于 `allow`
+    pub deny: Vec<IpNet>,
+    /// 每个 IP 每秒允许的请求数，0 表示不限制
+    pub requests_per_second: f64,
+    /// 允许的突发请求数
+    pub burst: u32,
+    /// 每个 IP 同时进行的 `/content` 与 `/blob` 下载数，0 表示不限制。
+    /// 不限制连接数，其他路径只受 `requests_per_second` 限制
+    pub max_concurrent_downloads: usize,
+}
+
+impl Default for AccessConfig {
+    fn default() -> Self {
+        Self {
+            allow: vec![],
+            deny: vec![],
+            requests_per_second: 0.0,
+            burst: 50,
+            max_concurrent_downloads: 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Forbidden;
+
+impl Reject for Forbidden {}
+
+#[derive(Debug)]
+pub struct TooManyRequests {
+    pub retry_after: u64,
+}
+
+impl Reject for TooManyRequests {}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// 访问控制：黑白名单、封禁列表和按 IP 的限流
+#[derive(Debug)]
+pub struct AccessControl {
+    config: Arc<Config>,
+    bans_path: PathBuf,
+    bans: std::sync::RwLock<Vec<IpNet>>,
+    buckets: DashMap<IpAddr, Bucket>,
+    downloads: DashMap<IpAddr, usize>,
+}
+
+impl AccessControl {
+    pub async fn load(config: Arc<Config>, bans_path: PathBuf) -> anyhow::Result<Self> {
+        let bans = if tokio::fs::try_exists(&bans_path).await? {
+            rmp_serde::from_slice(&tokio::fs::read(&bans_path).await?)?
+        } else {
+            vec![]
+        };
+        Ok(Self {
+            config,
+            bans_path,
+            bans: std::sync::RwLock::new(bans),
+            buckets: DashMap::new(),
+            downloads: DashMap::new(),
+        })
+    }
+
+    pub fn is_allowed(&self, ip: &IpAddr) -> bool {
+        let access = &self.config.access;
+        if access.deny.iter().any(|net| net.contains(ip)) {
+            return false;
+        }
+        if self.bans.read().unwrap().iter().any(|net| net.contains(ip)) {
+            return false;
+        }
+        access.allow.is_empty() || access.allow.iter().any(|net| net.contains(ip))
+    }
+
+    /// 令牌桶限流，超出时返回需要等待的秒数
+    pub fn check_rate(&self, ip: IpAddr) -> Result<(), u64> {
+        let rate = self.config.access.requests_per_second;
+        if rate <= 0.0 {
+            return Ok(());
+        }
+        let burst = self.config.access.burst.max(1) as f64;
+        let now = Instant::now();
+        let mut bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            last: now,
+        });
+        bucket.tokens = (bucket.tokens + (now - bucket.last).as_secs_f64() * rate).min(burst);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / rate).ceil().max(1.0) as u64)
+        }
+    }
+
+    /// 占用一个下载名额，返回的守卫在传输结束时释放
+    pub fn start_download(self: &Arc<Self>, ip: IpAddr) -> Option<DownloadSlot> {
+        let limit = self.config.access.max_concurrent_downloads;
+        let mut count = self.downloads.entry(ip).or_insert(0);
+        if limit > 0 && *count >= limit {
+            return None;
+        }
+        *count += 1;
+        Some(DownloadSlot {
+            access: self.clone(),
+            ip,
+        })
+    }
+
+    /// 清理已经回满的令牌桶
+    pub fn prune(&self) {
+        let rate = self.config.access.requests_per_second;
+        let burst = self.config.access.burst.max(1) as f64;
+        let now = Instant::now();
+        self.buckets
+            .retain(|_, bucket| bucket.tokens + (now - bucket.last).as_secs_f64() * rate < burst);
+        self.downloads.retain(|_, count| *count > 0);
+    }
+
+    pub async fn ban(&self, net: IpNet) -> anyhow::Result<()> {
+        {
+            let mut bans = self.bans.write().unwrap();
+            if bans.contains(&net) {
+                return Ok(());
+            }
+            bans.push(net);
+        }
+        log::warn!(target: "access", "Banned {net}");
+        self.save_bans().await
+    }
+
+    pub async fn unban(&self, net: IpNet) -> anyhow::Result<bool> {
+        let removed = {
+            let mut bans = self.bans.write().unwrap();
+            let len = bans.len();
+            bans.retain(|a| *a != net);
+            len != bans.len()
+        };
+        if removed {
+            log::info!(target: "access", "Unbanned {net}");
+            self.save_bans().await?;
+        }
+        Ok(removed)
+    }
+
+    pub fn bans(&self) -> Vec<IpNet> {
+        self.bans.read().unwrap().clone()
+    }
+
+    async fn save_bans(&self) -> anyhow::Result<()> {
+        let blob = rmp_serde::to_vec(&*self.bans.read().unwrap())?;
+        tokio::fs::write(&self.bans_path, blob).await?;
+        Ok(())
+    }
+}
+
+pub struct DownloadSlot {
+    access: Arc<AccessControl>,
+    ip: IpAddr,
+}
+
+impl Drop for DownloadSlot {
+    fn drop(&mut self) {
+        if let Some(mut count) = self.access.downloads.get_mut(&self.ip) {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
+
+/// 解析 `ban` / `unban` 的参数，单个地址视为 /32 或 /128
+pub fn parse_net(str: &str) -> Option<IpNet> {
+    str.parse()
+        .ok()
+        .or_else(|| str.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+/// 拒绝不允许的地址，并对超出频率的请求返回 429
+pub fn access_filter(
+    config: Arc<Config>,
+    access: Arc<AccessControl>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    get_ip(config)
+        .and_then(move |ci: ClientInfo| {
+            let access = access.clone();
+            async move {
+                let ip = ci.ip();
+                if !access.is_allowed(&ip) {
+                    log::warn!(target: "access", "{ip} => forbidden");
+                    return Err(warp::reject::custom(Forbidden));
+                }
+                if let Err(retry_after) = access.check_rate(ip) {
+                    log::warn!(target: "access", "{ip} => rate limited");
+                    return Err(warp::reject::custom(TooManyRequests { retry_after }));
+                }
+                Ok(())
+            }
+        })
+        .untuple_one()
+}
+
+pub fn too_many_requests(retry_after: u64) -> Response {
+    let mut response = StatusCode::TOO_MANY_REQUESTS.into_response();
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, retry_after.into());
+    response
+}
+
+pub async fn handle_rejection(err: Rejection) -> Result<Response, Rejection> {
+    if err.find::<Forbidden>().is_some() {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+    if let Some(e) = err.find::<TooManyRequests>() {
+        return Ok(too_many_requests(e.retry_after));
+    }
+    Err(err)
+}
+
+pub async fn prune_thread(access: Arc<AccessControl>) -> Result<(), Infallible> {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        access.prune();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn control(requests_per_second: f64, burst: u32) -> Arc<AccessControl> {
+        let mut config = Config::default();
+        config.access.requests_per_second = requests_per_second;
+        config.access.burst = burst;
+        config.access.max_concurrent_downloads = 1;
+        Arc::new(AccessControl {
+            config: Arc::new(config),
+            bans_path: PathBuf::new(),
+            bans: Default::default(),
+            buckets: DashMap::new(),
+            downloads: DashMap::new(),
+        })
+    }
+
+    fn rewind(access: &AccessControl, ip: IpAddr, elapsed: Duration) {
+        access.buckets.get_mut(&ip).unwrap().last -= elapsed;
+    }
+
+    #[test]
+    fn burst_then_limited() {
+        let access = control(2.0, 3);
+        let ip = "192.0.2.1".parse().unwrap();
+        for _ in 0..3 {
+            assert_eq!(access.check_rate(ip), Ok(()));
+        }
+        assert_eq!(access.check_rate(ip), Err(1));
+        // 其他地址有各自的令牌桶
+        assert_eq!(access.check_rate("192.0.2.2".parse().unwrap()), Ok(()));
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let access = control(2.0, 2);
+        let ip = "192.0.2.1".parse().unwrap();
+        assert!(access.check_rate(ip).is_ok());
+        assert!(access.check_rate(ip).is_ok());
+        assert!(access.check_rate(ip).is_err());
+
+        // 0.5 秒补充一个令牌
+        rewind(&access, ip, Duration::from_millis(600));
+        assert!(access.check_rate(ip).is_ok());
+        assert!(access.check_rate(ip).is_err());
+
+        // 补充的令牌不超过突发数量
+        rewind(&access, ip, Duration::from_secs(60));
+        assert!(access.check_rate(ip).is_ok());
+        assert!(access.check_rate(ip).is_ok());
+        assert!(access.check_rate(ip).is_err());
+    }
+
+    #[test]
+    fn retry_after_rounds_up() {
+        let access = control(0.25, 1);
+        let ip = "192.0.2.1".parse().unwrap();
+        assert!(access.check_rate(ip).is_ok());
+        assert_eq!(access.check_rate(ip), Err(4));
+    }
+
+    #[test]
+    fn zero_rate_is_unlimited() {
+        let access = control(0.0, 1);
+        let ip = "192.0.2.1".parse().unwrap();
+        for _ in 0..100 {
+            assert!(access.check_rate(ip).is_ok());
+        }
+        assert!(access.buckets.is_empty());
+    }
+
+    #[test]
+    fn prune_drops_full_buckets() {
+        let access = control(2.0, 2);
+        let ip = "192.0.2.1".parse().unwrap();
+        assert!(access.check_rate(ip).is_ok());
+        access.prune();
+        assert_eq!(access.buckets.len(), 1);
+        rewind(&access, ip, Duration::from_secs(1));
+        access.prune();
+        assert!(access.buckets.is_empty());
+    }
+
+    #[test]
+    fn download_slot_released_on_drop() {
+        let access = control(0.0, 1);
+        let ip = "192.0.2.1".parse().unwrap();
+        let slot = access.start_download(ip);
+        assert!(slot.is_some());
+        assert!(access.start_download(ip).is_none());
+        drop(slot);
+        assert!(access.start_download(ip).is_some());
+    }
+}
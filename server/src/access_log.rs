@@ -0,0 +1,177 @@
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime};
+
+use futures_util::Stream;
+use model::CLIENT_ID_HEADER;
+use serde::Serialize;
+use warp::{
+    filters::path::FullPath,
+    http::{header::*, HeaderMap, Method, StatusCode},
+    hyper::{body::Bytes, Body},
+    reject::Rejection,
+    reply::{Reply, Response},
+    Filter,
+};
+
+use crate::client_ip::{get_ip, ClientInfo};
+use crate::server_model::Config;
+
+/// JSON 访问日志使用的日志目标，在 `init_logger` 中单独配置输出
+pub const ACCESS_JSON_TARGET: &str = "access_json";
+
+/// 请求开始时记录的信息，在响应体传输结束时写入访问日志
+#[derive(Debug)]
+pub struct AccessEntry {
+    start: Instant,
+    ip: IpAddr,
+    method: Method,
+    path: String,
+    user_agent: Option<String>,
+    client_id: Option<String>,
+    range: Option<String>,
+    json: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct AccessRecord<'a> {
+    time: String,
+    ip: IpAddr,
+    method: &'a str,
+    path: &'a str,
+    status: u16,
+    bytes: u64,
+    duration_ms: u64,
+    user_agent: Option<&'a str>,
+    client_id: Option<&'a str>,
+    range: Option<&'a str>,
+    /// 响应体未传输完成连接就被关闭
+    aborted: bool,
+}
+
+pub fn access_entry(
+    config: Arc<Config>,
+) -> impl Filter<Extract = (AccessEntry,), Error = Rejection> + Clone {
+    let json = config.log.access_json;
+    get_ip(config)
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
+        .map(
+            move |ci: ClientInfo, method: Method, path: FullPath, headers: HeaderMap| {
+                let header = |name: &str| {
+                    headers
+                        .get(name)
+                        .and_then(|a| a.to_str().ok())
+                        .map(|a| a.to_string())
+                };
+                AccessEntry {
+                    start: Instant::now(),
+                    ip: ci.ip(),
+                    method,
+                    path: path.as_str().to_string(),
+                    user_agent: header(USER_AGENT.as_str()),
+                    client_id: header(CLIENT_ID_HEADER),
+                    range: header(RANGE.as_str()),
+                    json,
+                }
+            },
+        )
+}
+
+/// 包装响应体，统计发送的字节数并在传输结束（或连接中断）时写入访问日志
+pub fn log_response(entry: AccessEntry, reply: impl Reply) -> Response {
+    let (parts, body) = reply.into_response().into_parts();
+    let len = parts
+        .headers
+        .get(CONTENT_LENGTH)
+        .and_then(|a| a.to_str().ok())
+        .and_then(|a| a.parse().ok());
+    let body = LoggedBody {
+        inner: body,
+        status: parts.status,
+        len,
+        bytes: 0,
+        finished: false,
+        entry,
+    };
+    Response::from_parts(parts, Body::wrap_stream(body))
+}
+
+struct LoggedBody {
+    inner: Body,
+    entry: AccessEntry,
+    status: StatusCode,
+    /// 响应头中的 Content-Length，发送够这么多字节后 hyper 不会再轮询到流结束
+    len: Option<u64>,
+    bytes: u64,
+    finished: bool,
+}
+
+impl Stream for LoggedBody {
+    type Item = Result<Bytes, warp::hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(bytes))) => self.bytes += bytes.len() as u64,
+            Poll::Ready(None) => self.finished = true,
+            _ => {}
+        }
+        poll
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        let entry = &self.entry;
+        let duration_ms = entry.start.elapsed().as_millis() as u64;
+        let finished = self.finished || self.len.is_some_and(|len| self.bytes >= len);
+        // HEAD、204 和 304 响应没有响应体，hyper 不会轮询
+        let bodyless = entry.method == Method::HEAD
+            || self.status == StatusCode::NO_CONTENT
+            || self.status == StatusCode::NOT_MODIFIED;
+        let aborted = !finished && !bodyless;
+        let level = if self.status.is_client_error() || self.status.is_server_error() {
+            log::Level::Warn
+        } else {
+            log::Level::Info
+        };
+        log::log!(
+            target: "request",
+            level,
+            "{} \"{} {}\" {} {} {}ms range={} client={} ua={:?}{}",
+            entry.ip,
+            entry.method,
+            entry.path,
+            self.status.as_u16(),
+            self.bytes,
+            duration_ms,
+            entry.range.as_deref().unwrap_or("-"),
+            entry.client_id.as_deref().unwrap_or("-"),
+            entry.user_agent.as_deref().unwrap_or("-"),
+            if aborted { " aborted" } else { "" },
+        );
+        if entry.json {
+            let record = AccessRecord {
+                time: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+                ip: entry.ip,
+                method: entry.method.as_str(),
+                path: &entry.path,
+                status: self.status.as_u16(),
+                bytes: self.bytes,
+                duration_ms,
+                user_agent: entry.user_agent.as_deref(),
+                client_id: entry.client_id.as_deref(),
+                range: entry.range.as_deref(),
+                aborted,
+            };
+            match serde_json::to_string(&record) {
+                Ok(line) => log::info!(target: ACCESS_JSON_TARGET, "{line}"),
+                Err(e) => log::error!(target: "request", "Serialize access log failed: {e}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use model::ItemOp;
+
+use crate::snapshot::{Snapshot, SnapshotStore};
+
+/// 按内容哈希存放的文件，同一内容只保存一份
+///
+/// 路径为 `<hash 前两位>/<hash>`，文件一旦写入就不再改变，
+/// 不再被任何保留的快照引用时删除。快照目录中的文件是这里的硬链接，不额外占用空间
+#[derive(Debug)]
+pub struct BlobStore {
+    dir: PathBuf,
+    /// 快照中的文件是内容文件夹的硬链接，新文件需要复制而不能直接链接
+    shared: bool,
+    /// 发布与回收互斥，避免回收掉正在发布的快照的文件
+    lock: tokio::sync::Mutex<()>,
+    /// 不属于任何快照但不能回收的文件，如客户端更新
+    pinned: std::sync::Mutex<HashSet<String>>,
+}
+
+impl BlobStore {
+    /// 只打开目录，文件由调用方写入
+    pub async fn open(dir: PathBuf, shared: bool) -> anyhow::Result<Self> {
+        tokio::fs::create_dir_all(&dir).await?;
+        Ok(Self {
+            dir,
+            shared,
+            lock: tokio::sync::Mutex::new(()),
+            pinned: Default::default(),
+        })
+    }
+
+    /// 补齐已保留快照的文件并删除不再引用的文件
+    pub async fn load(
+        dir: PathBuf,
+        shared: bool,
+        snapshots: &SnapshotStore,
+    ) -> anyhow::Result<Self> {
+        let store = Self::open(dir, shared).await?;
+        for snapshot in snapshots.list() {
+            store.add(&snapshot).await?;
+        }
+        store.collect_garbage(&snapshots.list()).await?;
+        Ok(store)
+    }
+
+    /// 小写十六进制哈希，格式不正确时返回 `None`
+    pub fn path(&self, hash: &str) -> Option<PathBuf> {
+        if hash.len() != 64 || !hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+            return None;
+        }
+        Some(self.dir.join(&hash[..2]).join(hash))
+    }
+
+    /// 先写入新快照的文件再切换，切换后客户端立即可以按哈希下载
+    ///
+    /// 写入失败时删除未发布的快照，返回错误时一定没有切换
+    pub async fn publish(
+        &self,
+        snapshots: &SnapshotStore,
+        snapshot: Arc<Snapshot>,
+    ) -> anyhow::Result<()> {
+        let _lock = self.lock.lock().await;
+        if let Err(e) = self.add(&snapshot).await {
+            log::warn!(target: "blob", "Add blobs failed, discard {}: {e:?}", snapshot.version);
+            snapshot.retire();
+            return Err(e);
+        }
+        snapshots.publish(snapshot).await;
+        if let Err(e) = self.collect_garbage(&snapshots.list()).await {
+            log::warn!(target: "blob", "Collect garbage failed: {e:?}");
+        }
+        Ok(())
+    }
+
+    /// 写入 `(源文件, 哈希)` 并替换固定的文件，之前固定的文件在下次回收时删除
+    pub async fn pin(&self, files: &[(PathBuf, String)]) -> anyhow::Result<()> {
+        let _lock = self.lock.lock().await;
+        for (src, hash) in files {
+            let dst = self
+                .path(hash)
+                .ok_or_else(|| anyhow::anyhow!("无效的哈希 {hash}"))?;
+            if !tokio::fs::try_exists(&dst).await? {
+                store_file(src, &dst).await?;
+            }
+        }
+        *self.pinned.lock().unwrap() = files.iter().map(|a| a.1.clone()).collect();
+        Ok(())
+    }
+
+    async fn add(&self, snapshot: &Snapshot) -> anyhow::Result<()> {
+        let items: Vec<_> = snapshot
+            .manifest
+            .data
+            .iter()
+            .filter(|a| a.value().0 == ItemOp::Sync)
+            .map(|a| {
+                (
+                    a.key().clone(),
+                    base16ct::lower::encode_string(&a.value().2),
+                )
+            })
+            .collect();
+        let mut added = 0;
+        for (rel, hash) in items {
+            let dst = self.path(&hash).unwrap();
+            let src = rel
+                .split('/')
+                .fold(snapshot.dir.clone(), |path, seg| path.join(seg));
+            if !tokio::fs::try_exists(&dst).await? {
+                if self.shared || link_file(&src, &dst).await.is_err() {
+                    store_file(&src, &dst).await?;
+                }
+                added += 1;
+            }
+            // 快照中的文件改为链接到文件库，同一内容在各快照中只占用一份空间
+            if let Err(e) = link_file(&dst, &src).await {
+                log::warn!(target: "blob", "Link {src:?} to blob failed: {e}");
+            }
+        }
+        if added > 0 {
+            log::info!(target: "blob", "Added {added} blobs for snapshot {}", snapshot.version);
+        }
+        Ok(())
+    }
+
+    async fn collect_garbage(&self, snapshots: &[Arc<Snapshot>]) -> anyhow::Result<()> {
+        let mut referenced: HashSet<String> = snapshots
+            .iter()
+            .flat_map(|snapshot| {
+                snapshot
+                    .manifest
+                    .data
+                    .iter()
+                    .filter(|a| a.value().0 == ItemOp::Sync)
+                    .map(|a| base16ct::lower::encode_string(&a.value().2))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        referenced.extend(self.pinned.lock().unwrap().iter().cloned());
+
+        let mut removed = 0;
+        let mut read_dir = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            if !entry.file_type().await?.is_dir() {
+                continue;
+            }
+            let mut files = tokio::fs::read_dir(entry.path()).await?;
+            while let Some(file) = files.next_entry().await? {
+                if referenced.contains(&*file.file_name().to_string_lossy()) {
+                    continue;
+                }
+                // 进行中的下载持有文件句柄，删除失败时下次发布再回收
+                match tokio::fs::remove_file(file.path()).await {
+                    Ok(()) => removed += 1,
+                    Err(e) => log::warn!(target: "blob", "Remove {:?} failed: {e}", file.path()),
+                }
+            }
+        }
+        if removed > 0 {
+            log::info!(target: "blob", "Removed {removed} unreferenced blobs");
+        }
+        Ok(())
+    }
+}
+
+/// 以 `src` 的硬链接替换 `dst`，不同文件系统等无法链接时保留 `dst` 不变
+pub async fn link_file(src: &Path, dst: &Path) -> std::io::Result<()> {
+    if let Some(dir) = dst.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let mut tmp = dst.as_os_str().to_owned();
+    tmp.push(".link");
+    let tmp = PathBuf::from(tmp);
+    tokio::fs::hard_link(src, &tmp).await?;
+    let renamed = tokio::fs::rename(&tmp, dst).await;
+    // 已经是同一个文件时改名不做任何事，临时链接仍然存在
+    let _ = tokio::fs::remove_file(&tmp).await;
+    renamed
+}
+
+/// 写时复制到临时文件后改名，文件系统不支持时回退到复制
+///
+/// 用于可能被原地修改的文件：内容文件夹的硬链接、客户端更新程序
+pub async fn store_file(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    if let Some(dir) = dst.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let tmp = dst.with_extension("tmp");
+    let from = src.to_path_buf();
+    let to = tmp.clone();
+    tokio::task::spawn_blocking(move || reflink_copy::reflink_or_copy(from, to)).await??;
+    tokio::fs::rename(&tmp, dst).await?;
+    Ok(())
+}
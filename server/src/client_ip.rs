@@ -1,6 +1,10 @@
 use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 
-use warp::{filters::path::FullPath, http::Method, reject::Rejection, Filter};
+use ipnet::IpNet;
+use warp::{http::HeaderMap, reject::Rejection, Filter};
+
+use crate::server_model::Config;
 
 #[derive(Debug)]
 pub struct ClientInfo {
@@ -9,12 +13,8 @@ pub struct ClientInfo {
 }
 
 impl ClientInfo {
-    pub fn log(&self, path: &FullPath, method: &Method, correct: bool) {
-        if correct {
-            log::info!(target: "request", "{} => {} {}", self.ip, method, path.as_str());
-        } else {
-            log::warn!(target: "request", "{} => {} {}", self.ip, method, path.as_str());
-        }
+    pub fn ip(&self) -> IpAddr {
+        self.ip
     }
 }
 
@@ -23,58 +23,268 @@ pub enum ClientInfoSource {
     RemoteAddr,
     XRealIp,
     XForwardedFor,
+    Forwarded,
 }
 
-pub fn get_ip() -> impl Filter<Extract = (ClientInfo,), Error = Rejection> + Clone {
+/// 解析客户端地址
+///
+/// 只有 TCP 对端在 `trusted_proxies` 中时才采信转发头，
+/// 并从右向左跳过受信任的代理，第一个不受信任的地址即为客户端
+pub fn get_ip(
+    config: Arc<Config>,
+) -> impl Filter<Extract = (ClientInfo,), Error = Rejection> + Clone {
     warp::addr::remote()
-        .and(warp::header::optional::<String>("x-forwarded-for"))
-        .and(warp::header::optional::<String>("x-real-ip"))
-        .and_then(
-            |remote: Option<SocketAddr>, xff: Option<String>, xri: Option<String>| async move {
-                // 尝试从 X-Forwarded-For 获取
-                if let Some(ip) = parse_x_forwarded_for(&xff) {
+        .and(warp::header::headers_cloned())
+        .and_then(move |remote: Option<SocketAddr>, headers: HeaderMap| {
+            let config = config.clone();
+            async move {
+                let Some(remote) = remote else {
+                    return Err(warp::reject::not_found());
+                };
+                let peer = remote.ip();
+                let trusted =
+                    |ip: &IpAddr| config.trusted_proxies.iter().any(|net| net.contains(ip));
+
+                if !trusted(&peer) {
                     return Ok(ClientInfo {
-                        ip,
-                        source: ClientInfoSource::XForwardedFor,
+                        ip: peer,
+                        source: ClientInfoSource::RemoteAddr,
                     });
                 }
 
-                // 尝试从 X-Real-IP 获取
-                if let Some(ip) = parse_single_header(&xri) {
+                // 优先使用标准的 Forwarded 头
+                let forwarded = header_values(&headers, "forwarded");
+                if !forwarded.is_empty() {
+                    let chain = parse_forwarded(&forwarded);
+                    if let Some(ip) = walk_chain(&chain, &trusted) {
+                        return Ok(ClientInfo {
+                            ip,
+                            source: ClientInfoSource::Forwarded,
+                        });
+                    }
+                }
+
+                let xff = header_values(&headers, "x-forwarded-for");
+                if !xff.is_empty() {
+                    let chain: Vec<_> = xff
+                        .iter()
+                        .flat_map(|value| value.split(','))
+                        .map(|s| s.trim().parse().ok())
+                        .collect();
+                    if let Some(ip) = walk_chain(&chain, &trusted) {
+                        return Ok(ClientInfo {
+                            ip,
+                            source: ClientInfoSource::XForwardedFor,
+                        });
+                    }
+                }
+
+                if let Some(ip) = header_values(&headers, "x-real-ip")
+                    .first()
+                    .and_then(|value| value.trim().parse().ok())
+                {
                     return Ok(ClientInfo {
                         ip,
                         source: ClientInfoSource::XRealIp,
                     });
                 }
 
-                // 回退到 remote_addr
-                if let Some(addr) = remote {
-                    return Ok(ClientInfo {
-                        ip: addr.ip(),
-                        source: ClientInfoSource::RemoteAddr,
-                    });
-                }
+                Ok(ClientInfo {
+                    ip: peer,
+                    source: ClientInfoSource::RemoteAddr,
+                })
+            }
+        })
+}
 
-                return Err(warp::reject::not_found());
+fn header_values(headers: &HeaderMap, name: &str) -> Vec<String> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+        .collect()
+}
 
-                fn parse_single_header(header: &Option<String>) -> Option<IpAddr> {
-                    header.as_ref()?.parse().ok()
-                }
+/// 从右向左跳过受信任的代理；遇到无法解析的地址时停止，使用上一个受信任的地址
+fn walk_chain(chain: &[Option<IpAddr>], trusted: &impl Fn(&IpAddr) -> bool) -> Option<IpAddr> {
+    let mut last = None;
+    for ip in chain.iter().rev() {
+        let Some(ip) = ip else {
+            return last;
+        };
+        if !trusted(ip) {
+            return Some(*ip);
+        }
+        last = Some(*ip);
+    }
+    last
+}
 
-                fn parse_x_forwarded_for(xff: &Option<String>) -> Option<IpAddr> {
-                    let xff = xff.as_ref()?;
-                    xff.split(',').find_map(|s| s.trim().parse().ok())
+/// 解析 RFC 7239 `Forwarded` 头中的 `for=` 参数
+fn parse_forwarded(values: &[String]) -> Vec<Option<IpAddr>> {
+    values
+        .iter()
+        .flat_map(|value| value.split(','))
+        .map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                if !key.trim().eq_ignore_ascii_case("for") {
+                    return None;
                 }
-            },
-        )
+                Some(parse_node(value.trim().trim_matches('"')))
+            })?
+        })
+        .collect()
 }
 
-pub fn log_req(correct: bool) -> impl Filter<Extract = (), Error = Rejection> + Clone {
-    get_ip()
-        .and(warp::path::full())
-        .and(warp::method())
-        .map(move |ci: ClientInfo, path, method| {
-            ci.log(&path, &method, correct);
-        })
-        .untuple_one()
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Some(rest) = node.strip_prefix('[') {
+        // "[2001:db8::1]:4711"
+        return rest.split(']').next()?.parse().ok();
+    }
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    // "192.0.2.60:4711"
+    node.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(str: &str) -> IpAddr {
+        str.parse().unwrap()
+    }
+
+    async fn resolve(remote: &str, headers: &[(&str, &str)]) -> (IpAddr, String) {
+        let config = Config {
+            trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        };
+        let mut req = warp::test::request().remote_addr(remote.parse().unwrap());
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        let info = req.filter(&get_ip(Arc::new(config))).await.unwrap();
+        (info.ip, format!("{:?}", info.source))
+    }
+
+    #[tokio::test]
+    async fn untrusted_peer_ignores_headers() {
+        let headers = [
+            ("x-forwarded-for", "198.51.100.7"),
+            ("forwarded", "for=198.51.100.8"),
+            ("x-real-ip", "198.51.100.9"),
+        ];
+        let (ip, source) = resolve("203.0.113.1:1234", &headers).await;
+        assert_eq!(ip, self::ip("203.0.113.1"));
+        assert_eq!(source, "RemoteAddr");
+    }
+
+    #[tokio::test]
+    async fn skips_trusted_hops_from_the_right() {
+        let headers = [("x-forwarded-for", "198.51.100.7, 10.0.0.3, 10.0.0.2")];
+        let (ip, source) = resolve("10.0.0.1:1234", &headers).await;
+        assert_eq!(ip, self::ip("198.51.100.7"));
+        assert_eq!(source, "XForwardedFor");
+
+        // 客户端自己添加的地址在不受信任的一跳左侧，不被采信
+        let headers = [("x-forwarded-for", "1.1.1.1, 198.51.100.7, 10.0.0.2")];
+        let (ip, _) = resolve("10.0.0.1:1234", &headers).await;
+        assert_eq!(ip, self::ip("198.51.100.7"));
+    }
+
+    #[test]
+    fn repeated_headers_are_all_read() {
+        let mut headers = HeaderMap::new();
+        headers.append("x-forwarded-for", "198.51.100.7".parse().unwrap());
+        headers.append("x-forwarded-for", "10.0.0.2".parse().unwrap());
+        headers.append("x-forwarded-for", b"\xff"[..].try_into().unwrap());
+        assert_eq!(
+            header_values(&headers, "x-forwarded-for"),
+            vec!["198.51.100.7", "10.0.0.2"]
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_hop_stops_at_last_trusted() {
+        let headers = [("x-forwarded-for", "198.51.100.7, garbage, 10.0.0.2")];
+        let (ip, _) = resolve("10.0.0.1:1234", &headers).await;
+        assert_eq!(ip, self::ip("10.0.0.2"));
+    }
+
+    #[tokio::test]
+    async fn forwarded_takes_precedence() {
+        let headers = [
+            ("x-forwarded-for", "198.51.100.7"),
+            (
+                "forwarded",
+                "for=\"[2001:db8::1]:4711\";proto=https, for=10.0.0.2",
+            ),
+        ];
+        let (ip, source) = resolve("10.0.0.1:1234", &headers).await;
+        assert_eq!(ip, self::ip("2001:db8::1"));
+        assert_eq!(source, "Forwarded");
+    }
+
+    #[tokio::test]
+    async fn unusable_forwarded_falls_back() {
+        // 没有 for= 参数或为隐藏标识时改用 X-Forwarded-For
+        let headers = [
+            ("forwarded", "proto=https;by=10.0.0.2"),
+            ("x-forwarded-for", "198.51.100.7"),
+        ];
+        let (ip, source) = resolve("10.0.0.1:1234", &headers).await;
+        assert_eq!(ip, self::ip("198.51.100.7"));
+        assert_eq!(source, "XForwardedFor");
+
+        let headers = [
+            ("forwarded", "for=_hidden"),
+            ("x-real-ip", " 198.51.100.9 "),
+        ];
+        let (ip, source) = resolve("10.0.0.1:1234", &headers).await;
+        assert_eq!(ip, self::ip("198.51.100.9"));
+        assert_eq!(source, "XRealIp");
+    }
+
+    #[tokio::test]
+    async fn trusted_peer_without_headers() {
+        let headers = [("x-real-ip", "not an ip")];
+        let (ip, source) = resolve("10.0.0.1:1234", &headers).await;
+        assert_eq!(ip, self::ip("10.0.0.1"));
+        assert_eq!(source, "RemoteAddr");
+    }
+
+    #[test]
+    fn parses_forwarded_nodes() {
+        let values = vec![
+            "for=192.0.2.60:4711;proto=http, For=\"[2001:db8:cafe::17]\"".to_string(),
+            "for=unknown, by=10.0.0.1, for=198.51.100.1".to_string(),
+        ];
+        assert_eq!(
+            parse_forwarded(&values),
+            vec![
+                Some(ip("192.0.2.60")),
+                Some(ip("2001:db8:cafe::17")),
+                None,
+                None,
+                Some(ip("198.51.100.1")),
+            ]
+        );
+        assert_eq!(parse_node("[2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("[not-an-ip]:80"), None);
+        assert_eq!(parse_node("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node(""), None);
+    }
+
+    #[test]
+    fn walk_chain_all_trusted_returns_leftmost() {
+        let trusted = |ip: &IpAddr| matches!(ip, IpAddr::V4(v4) if v4.is_private());
+        let chain = vec![Some(ip("10.0.0.3")), Some(ip("10.0.0.2"))];
+        assert_eq!(walk_chain(&chain, &trusted), Some(ip("10.0.0.3")));
+        assert_eq!(walk_chain(&[None], &trusted), None);
+        assert_eq!(walk_chain(&[], &trusted), None);
+    }
 }
@@ -0,0 +1,184 @@
+use std::cmp::Reverse;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use dashmap::DashMap;
+use model::{SyncReport, CLIENT_ID_HEADER, CLIENT_VERSION_HEADER, PEER_PORT_HEADER};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use warp::{reject::Rejection, Filter};
+
+use crate::client_ip::{get_ip, ClientInfo};
+use crate::server_model::Config;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientRecord {
+    pub id: Uuid,
+    pub last_seen: SystemTime,
+    pub ip: IpAddr,
+    pub client_version: Option<String>,
+    /// 最近一次成功同步的清单版本
+    pub synced_version: Option<String>,
+    pub last_sync: Option<SystemTime>,
+    /// 失败的同步次数
+    pub failures: u64,
+    /// 向局域网其他客户端提供文件的端口，未开启时为空
+    #[serde(default)]
+    pub peer_port: Option<u16>,
+}
+
+/// `/clients` 返回的 JSON
+#[derive(Debug, Serialize)]
+pub struct ClientView {
+    pub id: Uuid,
+    pub last_seen: String,
+    pub ip: IpAddr,
+    pub client_version: Option<String>,
+    pub synced_version: Option<String>,
+    pub last_sync: Option<String>,
+    pub failures: u64,
+    /// 是否还未同步到当前版本
+    pub outdated: bool,
+}
+
+impl ClientRecord {
+    pub fn to_view(&self, current_version: &str) -> ClientView {
+        ClientView {
+            id: self.id,
+            last_seen: humantime::format_rfc3339_seconds(self.last_seen).to_string(),
+            ip: self.ip,
+            client_version: self.client_version.clone(),
+            synced_version: self.synced_version.clone(),
+            last_sync: self
+                .last_sync
+                .map(|a| humantime::format_rfc3339_seconds(a).to_string()),
+            failures: self.failures,
+            outdated: self.synced_version.as_deref() != Some(current_version),
+        }
+    }
+}
+
+/// 已知客户端的登记表，在收到同步报告时保存到磁盘
+#[derive(Debug)]
+pub struct ClientRegistry {
+    path: PathBuf,
+    clients: DashMap<Uuid, ClientRecord>,
+    /// 同一时间只有一次保存，避免并发的写入互相覆盖
+    save_lock: tokio::sync::Mutex<()>,
+}
+
+impl ClientRegistry {
+    pub async fn load(path: PathBuf) -> anyhow::Result<Self> {
+        let clients = if tokio::fs::try_exists(&path).await? {
+            let blob = tokio::fs::read(&path).await?;
+            match rmp_serde::from_slice(&blob) {
+                Ok(clients) => clients,
+                Err(e) => {
+                    log::warn!(target: "client", "Ignoring invalid client registry {path:?}: {e}");
+                    DashMap::new()
+                }
+            }
+        } else {
+            DashMap::new()
+        };
+        Ok(Self {
+            path,
+            clients,
+            save_lock: Default::default(),
+        })
+    }
+
+    /// 先写入临时文件再替换，中途退出不会留下不完整的登记表
+    async fn save(&self) -> anyhow::Result<()> {
+        let _guard = self.save_lock.lock().await;
+        let blob = rmp_serde::to_vec(&self.clients)?;
+        let tmp = self.path.with_extension("tmp");
+        tokio::fs::write(&tmp, blob).await?;
+        tokio::fs::rename(&tmp, &self.path).await?;
+        Ok(())
+    }
+
+    pub fn seen(&self, id: Uuid, ip: IpAddr, client_version: Option<String>) {
+        let now = SystemTime::now();
+        let mut record = self.clients.entry(id).or_insert_with(|| {
+            log::info!(target: "client", "New client {id} from {ip}");
+            ClientRecord {
+                id,
+                last_seen: now,
+                ip,
+                client_version: None,
+                synced_version: None,
+                last_sync: None,
+                failures: 0,
+                peer_port: None,
+            }
+        });
+        record.last_seen = now;
+        record.ip = ip;
+        if client_version.is_some() {
+            record.client_version = client_version;
+        }
+    }
+
+    pub async fn record_report(&self, ip: IpAddr, report: &SyncReport) -> anyhow::Result<()> {
+        self.seen(report.client_id, ip, Some(report.client_version.clone()));
+        if let Some(mut record) = self.clients.get_mut(&report.client_id) {
+            if report.is_success() {
+                record.synced_version = report.manifest_version.clone();
+                record.last_sync = Some(SystemTime::now());
+            } else {
+                record.failures += 1;
+            }
+        }
+        self.save().await
+    }
+
+    pub fn set_peer_port(&self, id: Uuid, peer_port: Option<u16>) {
+        if let Some(mut record) = self.clients.get_mut(&id) {
+            record.peer_port = peer_port;
+        }
+    }
+
+    /// 最近访问过且开启了文件共享的其他客户端
+    pub fn peers(&self, except: Option<Uuid>, ttl: Duration) -> Vec<SocketAddr> {
+        let now = SystemTime::now();
+        self.clients
+            .iter()
+            .filter(|a| Some(a.id) != except)
+            .filter(|a| now.duration_since(a.last_seen).is_ok_and(|d| d < ttl))
+            .filter_map(|a| Some(SocketAddr::new(a.ip, a.peer_port?)))
+            .collect()
+    }
+
+    /// 按最近访问时间倒序
+    pub fn list(&self) -> Vec<ClientRecord> {
+        let mut list: Vec<_> = self.clients.iter().map(|a| a.value().clone()).collect();
+        list.sort_by_key(|a| Reverse(a.last_seen));
+        list
+    }
+}
+
+/// 根据请求头中的客户端 ID 更新登记表，没有 ID 的请求（如浏览器）不做记录
+pub fn track_client(
+    config: Arc<Config>,
+    registry: Arc<ClientRegistry>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    get_ip(config)
+        .and(warp::header::optional::<String>(CLIENT_ID_HEADER))
+        .and(warp::header::optional::<String>(CLIENT_VERSION_HEADER))
+        .and(warp::header::optional::<u16>(PEER_PORT_HEADER))
+        .map(
+            move |ci: ClientInfo,
+                  id: Option<String>,
+                  version: Option<String>,
+                  peer_port: Option<u16>| {
+                if let Some(id) = id.and_then(|a| a.parse().ok()) {
+                    registry.seen(id, ci.ip(), version);
+                    registry.set_peer_port(id, peer_port);
+                }
+            },
+        )
+        .untuple_one()
+}
@@ -0,0 +1,240 @@
+use std::net::IpAddr;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures_util::Stream;
+use headers::{ETag, IfNoneMatch, Range};
+use model::{ItemOp, VERSION_HEADER};
+use percent_encoding::percent_decode_str;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+use warp::{
+    filters::path::Tail,
+    http::{header::*, Method, Response, StatusCode},
+    hyper::{body::Bytes, Body},
+    reject::Rejection,
+};
+
+use crate::access::{too_many_requests, AccessControl, DownloadSlot};
+use crate::blobs::BlobStore;
+use crate::metrics::{DownloadGuard, Metrics};
+use crate::relay::Relay;
+use crate::snapshot::{Snapshot, Snapshots};
+
+/// 一次文件下载请求的来源与参数
+pub struct FileRequest {
+    pub metrics: Arc<Metrics>,
+    pub access: Arc<AccessControl>,
+    pub ip: IpAddr,
+    pub method: Method,
+    pub range: Option<Range>,
+}
+
+/// 从当前快照中提供文件内容
+///
+/// 请求开始时取得快照的引用，并在响应体传输完成前一直持有，
+/// 保证重新加载期间进行中的下载不会读到新版本或被删除的文件
+pub async fn get_content(
+    snapshots: Snapshots,
+    relay: Option<Arc<Relay>>,
+    req: FileRequest,
+    tail: Tail,
+) -> Result<Response<Body>, Rejection> {
+    let snapshot = snapshots.current().await;
+
+    let path = percent_decode_str(tail.as_str())
+        .decode_utf8()
+        .map_err(|_| warp::reject::not_found())?;
+    let hash = match snapshot.manifest.data.get(&*path) {
+        Some(item) if item.0 == ItemOp::Sync => base16ct::lower::encode_string(&item.2),
+        _ => return Err(warp::reject::not_found()),
+    };
+    let file = match relay {
+        Some(relay) => match relay.fetch(&hash, Some(&path)).await {
+            Ok(file) => file,
+            Err(e) => {
+                log::warn!(target: "relay", "Fetch {path:?} from upstream failed: {e:?}");
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(Body::empty())
+                    .unwrap());
+            }
+        },
+        None => open_file(&sanitize_path(&snapshot, &path)?).await?,
+    };
+
+    let response = Response::builder().header(VERSION_HEADER, snapshot.version.to_string());
+    serve_file(file, response, req, &path, Some(snapshot)).await
+}
+
+/// 按内容哈希提供文件，内容不会改变，允许任意缓存
+pub async fn get_blob(
+    blobs: Arc<BlobStore>,
+    snapshots: Snapshots,
+    relay: Option<Arc<Relay>>,
+    req: FileRequest,
+    hash: String,
+    if_none_match: Option<IfNoneMatch>,
+) -> Result<Response<Body>, Rejection> {
+    let file_path = blobs.path(&hash).ok_or_else(warp::reject::not_found)?;
+    // 中继只提供当前清单引用的文件，任意哈希不会触发上游下载或挤掉缓存
+    let exists = match &relay {
+        Some(_) => snapshots.current().await.references(&hash),
+        None => tokio::fs::try_exists(&file_path).await.unwrap_or(false),
+    };
+    if !exists {
+        return Err(warp::reject::not_found());
+    }
+    let etag: ETag = format!("\"{hash}\"").parse().unwrap();
+
+    let response = Response::builder()
+        .header(ETAG, format!("\"{hash}\""))
+        .header(CACHE_CONTROL, "public, max-age=31536000, immutable");
+    if if_none_match.is_some_and(|a| !a.precondition_passes(&etag)) {
+        return Ok(response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap());
+    }
+
+    // 中继模式下只按哈希向上游请求，上游不支持时客户端会改用 /content
+    let file = match relay {
+        Some(relay) => match relay.fetch(&hash, None).await {
+            Ok(file) => file,
+            Err(e) => {
+                log::warn!(target: "relay", "Fetch blob {hash} from upstream failed: {e:?}");
+                return Err(warp::reject::not_found());
+            }
+        },
+        None => open_file(&file_path).await?,
+    };
+
+    serve_file(file, response, req, &format!("blob/{hash}"), None).await
+}
+
+async fn open_file(path: &Path) -> Result<tokio::fs::File, Rejection> {
+    tokio::fs::File::open(path)
+        .await
+        .map_err(|_| warp::reject::not_found())
+}
+
+/// 支持单区间请求的文件响应，GET 请求占用一个并发下载名额
+async fn serve_file(
+    mut file: tokio::fs::File,
+    response: warp::http::response::Builder,
+    req: FileRequest,
+    name: &str,
+    snapshot: Option<Arc<Snapshot>>,
+) -> Result<Response<Body>, Rejection> {
+    let len = file
+        .metadata()
+        .await
+        .map_err(|_| warp::reject::not_found())?
+        .len();
+
+    let response = response
+        .header(ACCEPT_RANGES, "bytes")
+        .header(CONTENT_TYPE, "application/octet-stream");
+
+    let (response, start, count) = match req.range.as_ref().and_then(|r| single_range(r, len)) {
+        Some(Ok((start, end))) => (
+            response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(CONTENT_RANGE, format!("bytes {start}-{}/{len}", end - 1)),
+            start,
+            end - start,
+        ),
+        Some(Err(())) => {
+            return Ok(Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(CONTENT_RANGE, format!("bytes */{len}"))
+                .body(Body::empty())
+                .unwrap());
+        }
+        None => (response.status(StatusCode::OK), 0, len),
+    };
+    let response = response.header(CONTENT_LENGTH, count);
+
+    if req.method == Method::HEAD {
+        return Ok(response.body(Body::empty()).unwrap());
+    }
+
+    let Some(slot) = req.access.start_download(req.ip) else {
+        log::warn!(target: "access", "{} => too many concurrent downloads", req.ip);
+        return Ok(too_many_requests(1));
+    };
+
+    if start > 0 {
+        file.seek(std::io::SeekFrom::Start(start))
+            .await
+            .map_err(|_| warp::reject::not_found())?;
+    }
+    let body = ContentStream {
+        inner: ReaderStream::new(file.take(count)),
+        download: req.metrics.start_download(name),
+        _slot: slot,
+        _snapshot: snapshot,
+    };
+    Ok(response.body(Body::wrap_stream(body)).unwrap())
+}
+
+/// 只支持单个区间，多个区间时返回完整内容
+fn single_range(range: &Range, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let mut ranges = range.satisfiable_ranges(len);
+    let first = ranges.next();
+    if ranges.next().is_some() {
+        return None;
+    }
+    let Some((start, end)) = first else {
+        return Some(Err(()));
+    };
+    let start = match start {
+        Bound::Included(start) => start,
+        Bound::Excluded(start) => start + 1,
+        Bound::Unbounded => 0,
+    };
+    let end = match end {
+        Bound::Included(end) => (end + 1).min(len),
+        Bound::Excluded(end) => end.min(len),
+        Bound::Unbounded => len,
+    };
+    if start >= end {
+        return Some(Err(()));
+    }
+    Some(Ok((start, end)))
+}
+
+fn sanitize_path(snapshot: &Snapshot, path: &str) -> Result<PathBuf, Rejection> {
+    let mut buf = snapshot.dir.clone();
+    for seg in path.split('/') {
+        if seg.starts_with("..") || seg.contains('\\') || (cfg!(windows) && seg.contains(':')) {
+            log::warn!(target: "request", "Rejecting content path {path:?}");
+            return Err(warp::reject::not_found());
+        }
+        buf.push(seg);
+    }
+    Ok(buf)
+}
+
+/// 文件内容流，按路径下载时持有快照引用直到传输结束
+struct ContentStream {
+    inner: ReaderStream<tokio::io::Take<tokio::fs::File>>,
+    download: DownloadGuard,
+    _slot: DownloadSlot,
+    _snapshot: Option<Arc<Snapshot>>,
+}
+
+impl Stream for ContentStream {
+    type Item = std::io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(bytes))) = &poll {
+            self.download.sent(bytes.len());
+        }
+        poll
+    }
+}
@@ -0,0 +1,93 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use model::run_hook;
+
+use crate::server_model::{Config, ManifestData};
+use crate::snapshot::Snapshot;
+
+/// 新版本相对旧版本新增、修改或删除的路径
+fn changed_paths(previous: Option<&ManifestData>, new: &ManifestData) -> Vec<String> {
+    let mut changed: Vec<String> = new
+        .data
+        .iter()
+        .filter(|a| {
+            previous.and_then(|old| old.data.get(a.key()).map(|b| *b.value() == *a.value()))
+                != Some(true)
+        })
+        .map(|a| a.key().clone())
+        .collect();
+    if let Some(old) = previous {
+        changed.extend(
+            old.data
+                .iter()
+                .filter(|a| !new.data.contains_key(a.key()))
+                .map(|a| a.key().clone()),
+        );
+    }
+    changed.sort();
+    changed
+}
+
+async fn publish_hook(
+    config: &Config,
+    stage: &str,
+    command: &[String],
+    previous: Option<&Snapshot>,
+    snapshot: &Snapshot,
+) -> anyhow::Result<()> {
+    let changed = changed_paths(previous.map(|a| &*a.manifest), &snapshot.manifest);
+    let envs = [
+        ("SYNER_STAGE", stage.to_string()),
+        ("SYNER_VERSION", snapshot.version.to_string()),
+        (
+            "SYNER_PREVIOUS_VERSION",
+            previous.map(|a| a.version.to_string()).unwrap_or_default(),
+        ),
+        (
+            "SYNER_SNAPSHOT_DIR",
+            snapshot.dir.to_string_lossy().to_string(),
+        ),
+        (
+            "SYNER_CONTENT_PATH",
+            config.content_path.to_string_lossy().to_string(),
+        ),
+    ];
+    let start = Instant::now();
+    run_hook(command, &envs, &changed).await?;
+    log::info!(target: "hook", "Publish hook {stage} finished in {:?}, {} changed", start.elapsed(), changed.len());
+    Ok(())
+}
+
+/// 新版本生效前执行，失败且配置为取消时删除未发布的快照并返回错误
+pub async fn before_publish(
+    config: &Config,
+    previous: Option<&Snapshot>,
+    snapshot: Arc<Snapshot>,
+) -> anyhow::Result<Arc<Snapshot>> {
+    let hooks = &config.hooks;
+    if hooks.before.is_empty() {
+        return Ok(snapshot);
+    }
+    let Err(e) = publish_hook(config, "before", &hooks.before, previous, &snapshot).await else {
+        return Ok(snapshot);
+    };
+    if !hooks.abort_on_failure {
+        log::warn!(target: "hook", "Publish hook before failed: {e:?}");
+        return Ok(snapshot);
+    }
+    log::warn!(target: "hook", "Publish hook before failed, discard {}: {e:?}", snapshot.version);
+    snapshot.retire();
+    Err(e.context("发布前的命令失败，已取消发布"))
+}
+
+/// 新版本生效后执行，失败只记录
+pub async fn after_publish(config: &Config, previous: Option<&Snapshot>, snapshot: &Snapshot) {
+    let hooks = &config.hooks;
+    if hooks.after.is_empty() {
+        return;
+    }
+    if let Err(e) = publish_hook(config, "after", &hooks.after, previous, snapshot).await {
+        log::warn!(target: "hook", "Publish hook after failed: {e:?}");
+    }
+}
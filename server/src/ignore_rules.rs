@@ -0,0 +1,135 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+
+/// 内容文件夹中的忽略规则文件，语法与 .gitignore 相同，对所在目录及其子目录生效
+pub const IGNORE_FILE: &str = ".synerignore";
+
+/// 从内容根目录到当前目录的忽略规则
+///
+/// 越深的规则优先，全局规则最后匹配；以 `!` 开头的规则可以重新包含被上层忽略的文件
+#[derive(Debug, Clone)]
+pub struct IgnoreRules {
+    stack: Arc<Vec<Gitignore>>,
+}
+
+impl IgnoreRules {
+    /// 以配置中的全局规则为底
+    pub fn new(root: &Path, patterns: &[String]) -> anyhow::Result<Self> {
+        let mut builder = GitignoreBuilder::new(root);
+        for pattern in patterns {
+            builder.add_line(None, pattern)?;
+        }
+        Ok(Self {
+            stack: Arc::new(vec![builder.build()?]),
+        })
+    }
+
+    /// 进入目录时读取其中的忽略规则文件
+    pub fn enter(&self, dir: &Path) -> anyhow::Result<Self> {
+        let path = dir.join(IGNORE_FILE);
+        if !path.is_file() {
+            return Ok(self.clone());
+        }
+        let mut builder = GitignoreBuilder::new(dir);
+        if let Some(e) = builder.add(&path) {
+            log::warn!(target: "manifest", "Invalid rule in {path:?}: {e}");
+        }
+        let mut stack = (*self.stack).clone();
+        stack.push(builder.build()?);
+        Ok(Self {
+            stack: Arc::new(stack),
+        })
+    }
+
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if path.file_name().is_some_and(|name| name == IGNORE_FILE) {
+            return true;
+        }
+        for rules in self.stack.iter().rev() {
+            match rules.matched(path, is_dir) {
+                Match::None => continue,
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    /// 测试用的内容文件夹，结束时删除
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("syner-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn global_patterns() {
+        let root = TempDir::new("ignore-global");
+        let rules = IgnoreRules::new(&root.0, &["*.tmp".into(), "cache/".into()]).unwrap();
+        assert!(rules.is_ignored(&root.0.join("a.tmp"), false));
+        assert!(rules.is_ignored(&root.0.join("sub/b.tmp"), false));
+        assert!(!rules.is_ignored(&root.0.join("a.txt"), false));
+        // 以 `/` 结尾的规则只匹配文件夹
+        assert!(rules.is_ignored(&root.0.join("cache"), true));
+        assert!(!rules.is_ignored(&root.0.join("cache"), false));
+    }
+
+    #[test]
+    fn nested_rules_override_parents() {
+        let root = TempDir::new("ignore-nested");
+        let sub = root.0.join("sub");
+        std::fs::create_dir_all(&sub).unwrap();
+        std::fs::write(root.0.join(IGNORE_FILE), "*.log\n").unwrap();
+        std::fs::write(sub.join(IGNORE_FILE), "!keep.log\n*.bak\n").unwrap();
+
+        let rules = IgnoreRules::new(&root.0, &["*.bin".into()]).unwrap();
+        let top = rules.enter(&root.0).unwrap();
+        let nested = top.enter(&sub).unwrap();
+
+        assert!(top.is_ignored(&root.0.join("keep.log"), false));
+        assert!(!nested.is_ignored(&sub.join("keep.log"), false));
+        assert!(nested.is_ignored(&sub.join("other.log"), false));
+        assert!(nested.is_ignored(&sub.join("a.bak"), false));
+        assert!(!top.is_ignored(&root.0.join("a.bak"), false));
+        // 全局规则在所有目录中生效
+        assert!(nested.is_ignored(&sub.join("a.bin"), false));
+    }
+
+    #[test]
+    fn rule_files_are_never_published() {
+        let root = TempDir::new("ignore-self");
+        let rules = IgnoreRules::new(&root.0, &["!*".into()]).unwrap();
+        assert!(rules.is_ignored(&root.0.join(IGNORE_FILE), false));
+        assert!(rules.is_ignored(&root.0.join("sub").join(IGNORE_FILE), false));
+    }
+
+    #[test]
+    fn directory_without_rule_file_reuses_parent() {
+        let root = TempDir::new("ignore-empty");
+        let rules = IgnoreRules::new(&root.0, &[]).unwrap();
+        let entered = rules.enter(&root.0).unwrap();
+        assert!(Arc::ptr_eq(&rules.stack, &entered.stack));
+        assert!(!entered.is_ignored(&root.0.join("a.txt"), false));
+    }
+}
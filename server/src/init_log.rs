@@ -1,31 +1,211 @@
-use chrono::prelude::*;
 use log::LevelFilter;
 use log4rs::append::console::ConsoleAppender;
-use log4rs::append::file::FileAppender;
+use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
+use log4rs::append::rolling_file::policy::compound::trigger::Trigger;
+use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
+use log4rs::append::rolling_file::{LogFile, RollingFileAppender};
 use log4rs::config::{Appender, Config, Logger, Root};
 use log4rs::encode::pattern::PatternEncoder;
-use std::time::SystemTime;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
-pub fn init_logger() -> anyhow::Result<()> {
-    let now = Local::now().format("%Y-%m-%d-%H-%M-%S");
+use crate::access_log::ACCESS_JSON_TARGET;
 
+const LOG_FILE: &str = "syner_server";
+
+const ACCESS_JSON_FILE: &str = "access";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    /// 日志文件夹
+    pub dir: PathBuf,
+    /// 默认日志级别
+    pub level: LevelFilter,
+    /// 按目标设置日志级别，如 `manifest = "debug"`
+    pub targets: BTreeMap<String, LevelFilter>,
+    /// 单个日志文件的最大大小（MB），0 表示不按大小切分
+    pub max_size_mb: u64,
+    /// 按时间切分的间隔，0 表示不按时间切分
+    #[serde(with = "crate::utils::humantime_duration")]
+    pub rotate_interval: Duration,
+    /// 保留的已切分日志文件数量
+    pub keep: u32,
+    /// 同时将访问日志以 JSON 行写入 `access.jsonl`
+    pub access_json: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            dir: "./logs".into(),
+            level: LevelFilter::Info,
+            targets: BTreeMap::new(),
+            max_size_mb: 10,
+            rotate_interval: Duration::from_secs(24 * 60 * 60),
+            keep: 10,
+            access_json: false,
+        }
+    }
+}
+
+/// 运行时调整日志级别，修改后重新生成 log4rs 配置
+pub struct LogHandle {
+    handle: log4rs::Handle,
+    config: LogConfig,
+    levels: Mutex<(LevelFilter, BTreeMap<String, LevelFilter>)>,
+    /// 按时间切分的下次切分时间，重新生成配置时保留
+    next_rolls: [Arc<Mutex<SystemTime>>; 2],
+}
+
+impl LogHandle {
+    /// `target` 为 `root` 时修改默认级别
+    pub fn set_level(&self, target: &str, level: LevelFilter) -> anyhow::Result<()> {
+        let mut levels = self.levels.lock().unwrap();
+        // 配置生成失败时保留原来的级别
+        let mut new = levels.clone();
+        if target == "root" {
+            new.0 = level;
+        } else {
+            new.1.insert(target.to_string(), level);
+        }
+        let config = build_config(&self.config, new.0, &new.1, &self.next_rolls)?;
+        self.handle.set_config(config);
+        *levels = new;
+        Ok(())
+    }
+
+    pub fn levels(&self) -> (LevelFilter, BTreeMap<String, LevelFilter>) {
+        self.levels.lock().unwrap().clone()
+    }
+}
+
+pub fn init_logger(config: &LogConfig) -> anyhow::Result<LogHandle> {
+    std::fs::create_dir_all(&config.dir)?;
+    let next_rolls = [
+        initial_roll(config, &log_path(config, LOG_FILE, "log")),
+        initial_roll(config, &log_path(config, ACCESS_JSON_FILE, "jsonl")),
+    ];
+    let log_config = build_config(config, config.level, &config.targets, &next_rolls)?;
+    let handle = log4rs::init_config(log_config)?;
+    Ok(LogHandle {
+        handle,
+        config: config.clone(),
+        levels: Mutex::new((config.level, config.targets.clone())),
+        next_rolls,
+    })
+}
+
+fn build_config(
+    config: &LogConfig,
+    root: LevelFilter,
+    targets: &BTreeMap<String, LevelFilter>,
+    next_rolls: &[Arc<Mutex<SystemTime>>; 2],
+) -> anyhow::Result<Config> {
     let pattern = Box::new(PatternEncoder::new(
         "[{d(%Y-%m-%d %H:%M:%S%.6f)}][{h({l})}][{t}] {m}{n}",
     ));
     let stdout = ConsoleAppender::builder().encoder(pattern.clone()).build();
+    let file = rolling_file(config, LOG_FILE, "log", pattern, next_rolls[0].clone())?;
 
-    let file = FileAppender::builder()
-        .encoder(pattern)
-        .build(format!("./logs/{now}.log"))?;
-    let config = Config::builder()
+    let mut builder = Config::builder()
         .appender(Appender::builder().build("stdout", Box::new(stdout)))
-        .appender(Appender::builder().build("file", Box::new(file)))
-        .build(
-            Root::builder()
-                .appenders(["stdout", "file"])
-                .build(LevelFilter::Info),
+        .appender(Appender::builder().build("file", Box::new(file)));
+
+    // JSON 访问日志只写入单独的文件，不输出到控制台和主日志
+    let access_json = Logger::builder().additive(false);
+    let access_json = if config.access_json {
+        let json = rolling_file(
+            config,
+            ACCESS_JSON_FILE,
+            "jsonl",
+            Box::new(PatternEncoder::new("{m}{n}")),
+            next_rolls[1].clone(),
         )?;
+        builder = builder.appender(Appender::builder().build("access_json", Box::new(json)));
+        let level = targets
+            .get(ACCESS_JSON_TARGET)
+            .copied()
+            .unwrap_or(LevelFilter::Info);
+        access_json
+            .appender("access_json")
+            .build(ACCESS_JSON_TARGET, level)
+    } else {
+        access_json.build(ACCESS_JSON_TARGET, LevelFilter::Off)
+    };
+    builder = builder.logger(access_json);
+
+    // 内置的 logger 已使用配置的级别，不能重复添加
+    for (target, level) in targets.iter().filter(|a| a.0 != ACCESS_JSON_TARGET) {
+        builder = builder.logger(Logger::builder().build(target, *level));
+    }
+
+    Ok(builder.build(Root::builder().appenders(["stdout", "file"]).build(root))?)
+}
+
+fn log_path(config: &LogConfig, name: &str, ext: &str) -> PathBuf {
+    config.dir.join(format!("{name}.{ext}"))
+}
+
+/// 当前日志文件为 `<name>.<ext>`，切分后的文件为 `<name>.<序号>.<ext>`，序号越大越旧
+fn rolling_file(
+    config: &LogConfig,
+    name: &str,
+    ext: &str,
+    encoder: Box<PatternEncoder>,
+    next_roll: Arc<Mutex<SystemTime>>,
+) -> anyhow::Result<RollingFileAppender> {
+    let pattern = config.dir.join(format!("{name}.{{}}.{ext}"));
+    let roller = FixedWindowRoller::builder()
+        .base(1)
+        .build(&pattern.to_string_lossy(), config.keep)?;
+    let trigger = RotateTrigger {
+        max_size: config.max_size_mb * 1024 * 1024,
+        interval: config.rotate_interval,
+        next_roll,
+    };
+    Ok(RollingFileAppender::builder().encoder(encoder).build(
+        log_path(config, name, ext),
+        Box::new(CompoundPolicy::new(Box::new(trigger), Box::new(roller))),
+    )?)
+}
+
+/// 已有的日志文件从其创建时间开始计算下次切分时间
+fn initial_roll(config: &LogConfig, path: &Path) -> Arc<Mutex<SystemTime>> {
+    let created = std::fs::metadata(path)
+        .and_then(|meta| meta.created())
+        .unwrap_or_else(|_| SystemTime::now());
+    Arc::new(Mutex::new(created + config.rotate_interval))
+}
+
+/// 文件超过大小或到达切分时间时切分
+#[derive(Debug)]
+struct RotateTrigger {
+    max_size: u64,
+    interval: Duration,
+    next_roll: Arc<Mutex<SystemTime>>,
+}
+
+impl Trigger for RotateTrigger {
+    fn trigger(&self, file: &LogFile) -> anyhow::Result<bool> {
+        if self.max_size > 0 && file.len_estimate() > self.max_size {
+            return Ok(true);
+        }
+        if !self.interval.is_zero() {
+            let now = SystemTime::now();
+            let mut next_roll = self.next_roll.lock().unwrap();
+            if now >= *next_roll {
+                *next_roll = now + self.interval;
+                return Ok(file.len_estimate() > 0);
+            }
+        }
+        Ok(false)
+    }
 
-    log4rs::init_config(config)?;
-    Ok(())
+    fn is_pre_process(&self) -> bool {
+        true
+    }
 }
@@ -10,49 +10,92 @@ use std::path::{Path, PathBuf};
 use std::process::abort;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use dashmap::DashMap;
-use headers::{Header, Range};
+use headers::{Header, IfNoneMatch, Range};
 use hyper::header::HeaderValue;
-use log::info;
-use model::{calc_hash, ItemOp, Manifest};
+use log::{info, LevelFilter};
+use model::{
+    calc_hash, ItemOp, ItemOutcome, Manifest, SyncReport, CLIENT_ID_HEADER, MANIFEST_FORMAT_HEADER,
+};
 use serde_bytes::ByteBuf;
 use sha3::{Digest, Sha3_256};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::task::JoinSet;
 use ulid::Ulid;
 use url::Url;
+use uuid::Uuid;
+use warp::http::header::{AUTHORIZATION, CONTENT_TYPE};
 use warp::http::StatusCode;
 use warp::reject::Rejection;
-use warp::Filter;
+use warp::{Filter, Reply};
 
+mod access;
+mod access_log;
+mod blobs;
 mod client_ip;
+mod clients;
+mod content;
+mod hooks;
+mod ignore_rules;
 mod init_log;
+mod metrics;
 mod print;
+mod relay;
+mod replication;
+mod reports;
 mod server_model;
+mod snapshot;
+mod tombstones;
+mod updates;
 mod utils;
 
+use access::*;
+use access_log::*;
+use blobs::*;
 use client_ip::*;
+use clients::*;
+use content::*;
+use hooks::*;
+use ignore_rules::*;
 use init_log::*;
+use metrics::*;
 use print::*;
+use relay::*;
+use replication::*;
+use reports::*;
 use server_model::*;
+use snapshot::*;
+use tombstones::*;
+use updates::*;
 use utils::*;
 
 const CONFIG_PATH: &'static str = "./syner_server.toml";
 
 const BACKUP_PATH: &'static str = "./.c/";
 
+const REPORT_PATH: &str = "./.reports/";
+
+const CLIENTS_PATH: &str = "./.clients";
+
+const BANS_PATH: &str = "./.bans";
+
+const BLOB_PATH: &str = "./.blobs/";
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let console = has_console();
+
     let config = if !tokio::fs::try_exists(CONFIG_PATH).await? {
-        first_config(&PathBuf::from(CONFIG_PATH)).await?
+        first_config(&PathBuf::from(CONFIG_PATH), console).await?
     } else {
         let config_str = tokio::fs::read_to_string(CONFIG_PATH).await?;
         toml::from_str(&config_str)?
     };
     let config = Arc::new(config);
 
-    init_logger()?;
+    let log = Arc::new(init_logger(&config.log)?);
 
     let content_path = Arc::new(config.content_path.clone());
     let backup_path = Arc::new(PathBuf::from_str(BACKUP_PATH)?);
@@ -60,40 +103,153 @@ async fn main() -> anyhow::Result<()> {
     tokio::fs::create_dir_all(&*content_path).await?;
     tokio::fs::create_dir_all(&*backup_path).await?;
 
-    let manifest = Arc::new(tokio::sync::RwLock::new(
-        collect_manifest(config.clone(), content_path.clone()).await?,
+    let metrics = Arc::new(Metrics::new(config.metrics_top_files)?);
+
+    let relay = match &config.relay.upstream {
+        Some(upstream) => Some(Arc::new(
+            Relay::load(&config.relay, upstream.clone()).await?,
+        )),
+        None => None,
+    };
+
+    let history = SnapshotStore::load_history(&backup_path).await?;
+    let start = Instant::now();
+    let latest = history.iter().max_by_key(|a| a.version).cloned();
+    let previous = latest.clone();
+    let mut published = relay.is_none() && !config.replication.follower;
+    let mut cancelled = false;
+    let snapshot = match (&relay, latest) {
+        (Some(relay), _) => match relay.fetch_manifest().await {
+            Ok((version, manifest)) => relay_snapshot(&backup_path, version, manifest).await,
+            Err(e) => Err(e),
+        },
+        // 从服务器继续提供上次推送的版本
+        (None, Some(latest)) if config.replication.follower => Ok(latest),
+        // 从服务器不发布 content_path，收到第一次推送前清单返回 503
+        (None, None) if config.replication.follower => {
+            log::info!(target: "replication", "Waiting for the first push from the leader");
+            Snapshot::placeholder().map(Arc::new)
+        }
+        (None, _) => {
+            let new = publish_snapshot(config.clone(), content_path.clone(), backup_path.clone());
+            let res = match new.await {
+                Ok(snapshot) => before_publish(&config, previous.as_deref(), snapshot).await,
+                Err(e) => Err(e),
+            };
+            match (res, &previous) {
+                // 发布失败或被发布前的命令取消时继续提供上一个版本
+                (Err(e), Some(previous)) => {
+                    log::warn!(target: "manifest", "Publish failed, serving {}: {e:?}", previous.version);
+                    published = false;
+                    cancelled = true;
+                    Ok(previous.clone())
+                }
+                (res, _) => res,
+            }
+        }
+    };
+    metrics.observe_reload(start.elapsed(), snapshot.is_ok() && !cancelled);
+    let snapshot = match snapshot {
+        // 上游暂时不可用时继续提供上次获取的版本
+        Err(e) if relay.is_some() && !history.is_empty() => {
+            log::warn!(target: "relay", "Fetch upstream manifest failed, using last snapshot: {e:?}");
+            history.iter().max_by_key(|a| a.version).unwrap().clone()
+        }
+        snapshot => snapshot?,
+    };
+    let snapshots = Arc::new(SnapshotStore::new(
+        config.snapshot_retention,
+        snapshot,
+        history,
     ));
-    ready_for_backup(backup_path.clone()).await?;
-    backup_content(
-        config.clone(),
-        content_path.clone(),
-        backup_path.clone(),
-        &*content_path,
-    )
-    .await?;
 
-    let mut set = JoinSet::new();
-    set.spawn(server_thread(config.clone(), manifest.clone()));
-    set.spawn(input_watch_thread(
+    let blobs = match &relay {
+        Some(relay) => {
+            relay.retain(&snapshots.current().await.manifest);
+            relay.cache()
+        }
+        None => Arc::new(
+            BlobStore::load(
+                PathBuf::from_str(BLOB_PATH)?,
+                config.freeze_mode == FreezeMode::HardLink,
+                &snapshots,
+            )
+            .await?,
+        ),
+    };
+    let updates = match &relay {
+        Some(_) => None,
+        None => Some(Arc::new(
+            ClientUpdates::load(config.update_path.clone(), &blobs).await?,
+        )),
+    };
+    if published {
+        let current = snapshots.current().await;
+        after_publish(&config, previous.as_deref(), &current).await;
+    }
+
+    let reports = Arc::new(ReportStore::load(PathBuf::from_str(REPORT_PATH)?).await?);
+    let clients = Arc::new(ClientRegistry::load(PathBuf::from_str(CLIENTS_PATH)?).await?);
+    let access =
+        Arc::new(AccessControl::load(config.clone(), PathBuf::from_str(BANS_PATH)?).await?);
+    let replication = &config.replication;
+    let replicator = (!replication.follower
+        && !replication.token.is_empty()
+        && !replication.followers.is_empty())
+    .then(|| Arc::new(Replicator::new(replication)));
+
+    let state = Arc::new(ServerState {
         config,
         content_path,
         backup_path,
-        manifest,
-    ));
+        snapshots,
+        blobs,
+        metrics,
+        reports,
+        clients,
+        access,
+        log,
+        relay,
+        replicator,
+        updates,
+    });
 
-    sprintln!()?;
-    print_help()?;
-    sprintln!()?;
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
 
-    while let Some(res) = set.join_next().await {
-        res??;
+    let mut set = JoinSet::new();
+    let server_id = set.spawn(server_thread(state.clone(), shutdown_rx)).id();
+    set.spawn(signal_thread(state.clone(), shutdown_tx.clone()));
+    if console {
+        set.spawn(input_watch_thread(state, shutdown_tx));
+
+        sprintln!()?;
+        print_help()?;
+        sprintln!()?;
+    } else {
+        log::info!("stdin is not a terminal, console disabled, rollback is unavailable");
     }
 
-    Ok(())
+    while let Some(res) = set.join_next_with_id().await {
+        let (id, res) = res?;
+        res?;
+        if id == server_id {
+            break;
+        }
+    }
+
+    log::info!("Server stopped");
+    log::logger().flush();
+    // 控制台线程可能仍阻塞在 stdin 上，不能等待运行时正常退出
+    std::process::exit(0)
 }
 
-async fn first_config(config_path: &Path) -> anyhow::Result<Config> {
+async fn first_config(config_path: &Path, console: bool) -> anyhow::Result<Config> {
     let mut config = Config::default();
+    if !console {
+        let config_str = toml::to_string_pretty(&config)?;
+        tokio::fs::write(config_path, &config_str).await?;
+        return Ok(config);
+    }
     sprintln!("不存在配置文件，开始初次配置")?;
 
     loop {
@@ -134,14 +290,10 @@ async fn first_config(config_path: &Path) -> anyhow::Result<Config> {
         }
     }
 
-    loop {
-        sprint!("标记删除的文件的后缀 （默认 del）：")?;
-        let str = read_line()?;
-        if str.is_empty() {
-            break;
-        }
+    sprint!("标记删除的文件的后缀 （默认 del）：")?;
+    let str = read_line()?;
+    if !str.is_empty() {
         config.remove_ext = str;
-        break;
     }
 
     let config_str = toml::to_string_pretty(&config)?;
@@ -151,25 +303,81 @@ async fn first_config(config_path: &Path) -> anyhow::Result<Config> {
 }
 
 async fn input_watch_thread(
-    config: Arc<Config>,
-    contnet_path: Arc<PathBuf>,
-    backup_path: Arc<PathBuf>,
-    manifest: Arc<tokio::sync::RwLock<Arc<ManifestData>>>,
+    state: Arc<ServerState>,
+    shutdown: tokio::sync::watch::Sender<bool>,
 ) -> anyhow::Result<()> {
     loop {
-        let str = tokio::task::spawn_blocking(read_line).await??;
-        match &*str {
+        let str = match tokio::task::spawn_blocking(read_line).await? {
+            Ok(str) => str,
+            Err(e) if is_eof(&e) => {
+                log::info!("stdin closed, console disabled");
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let line = str.trim();
+        let (cmd, arg) = line
+            .split_once(' ')
+            .map(|(cmd, arg)| (cmd, arg.trim()))
+            .unwrap_or((line, ""));
+        match cmd {
+            "" => {}
             "?" | "h" | "help" => print_help()?,
-            "q" | "quit" | "exit" | "stop" => std::process::exit(0),
+            "q" | "quit" | "exit" | "stop" => {
+                shutdown.send_replace(true);
+                return Ok(());
+            }
             "r" | "reload" => {
-                re_collect_manifest(
-                    config.clone(),
-                    contnet_path.clone(),
-                    backup_path.clone(),
-                    manifest.clone(),
-                )
-                .await?
+                // 发布中文件被修改等错误只取消本次发布，不退出服务器
+                if let Err(e) = re_collect_manifest(state.clone()).await {
+                    log::error!(target: "manifest", "Reload failed: {e:?}");
+                    sprintln!("重新加载失败：{e:#}")?;
+                }
+            }
+            "l" | "list" => print_snapshots(&state.snapshots).await?,
+            "reports" => print_reports(&state).await?,
+            "clients" => print_clients(&state).await?,
+            "ban" => match parse_net(arg) {
+                Some(net) => {
+                    state.access.ban(net).await?;
+                    sprintln!("已封禁 {net}")?;
+                }
+                None => sprintln!("用法: ban <IP 或 CIDR>")?,
+            },
+            "unban" => match parse_net(arg) {
+                Some(net) => {
+                    if state.access.unban(net).await? {
+                        sprintln!("已解封 {net}")?;
+                    } else {
+                        sprintln!("{net} 不在封禁列表中")?;
+                    }
+                }
+                None => sprintln!("用法: unban <IP 或 CIDR>")?,
+            },
+            "bans" => {
+                for net in state.access.bans() {
+                    sprintln!("{net}")?;
+                }
+            }
+            "log" => {
+                if let Err(e) = set_log_level(&state, arg) {
+                    sprintln!("{e}")?;
+                }
             }
+            "ignored" => print_ignored(&state).await?,
+            "replicas" => print_replicas(&state).await?,
+            "rollback" => match Ulid::from_string(arg) {
+                Ok(version) => match state.snapshots.rollback(version).await {
+                    Ok(_) => {
+                        if let Some(replicator) = &state.replicator {
+                            replicator.trigger();
+                        }
+                        sprintln!("已回滚到 {version}")?
+                    }
+                    Err(e) => sprintln!("{e}")?,
+                },
+                Err(_) => sprintln!("用法: rollback <版本>")?,
+            },
             _ => {
                 sprintln!("未知指令")?;
                 print_help()?;
@@ -178,13 +386,202 @@ async fn input_watch_thread(
     }
 }
 
+/// SIGHUP 重新加载清单，SIGTERM / SIGINT 优雅退出
+#[cfg(unix)]
+async fn signal_thread(
+    state: Arc<ServerState>,
+    shutdown: tokio::sync::watch::Sender<bool>,
+) -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                log::info!(target: "signal", "SIGHUP received, reloading manifest");
+                if let Err(e) = re_collect_manifest(state.clone()).await {
+                    log::error!(target: "signal", "Reload failed: {e:?}");
+                }
+            }
+            _ = terminate.recv() => {
+                log::info!(target: "signal", "SIGTERM received, shutting down");
+                break;
+            }
+            _ = interrupt.recv() => {
+                log::info!(target: "signal", "SIGINT received, shutting down");
+                break;
+            }
+        }
+    }
+
+    shutdown.send_replace(true);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn signal_thread(
+    state: Arc<ServerState>,
+    shutdown: tokio::sync::watch::Sender<bool>,
+) -> anyhow::Result<()> {
+    tokio::signal::ctrl_c().await?;
+    log::info!(target: "signal", "Ctrl-C received, shutting down");
+    shutdown.send_replace(true);
+    Ok(())
+}
+
+async fn print_snapshots(snapshots: &Snapshots) -> anyhow::Result<()> {
+    let current = snapshots.current().await.version;
+    for item in snapshots.list() {
+        let time = chrono::DateTime::<chrono::Local>::from(item.version.datetime());
+        sprintln!(
+            "{} {} {} {} 个文件",
+            if item.version == current { "*" } else { " " },
+            item.version,
+            time.format("%Y-%m-%d %H:%M:%S"),
+            item.manifest.data.len(),
+        )?;
+    }
+    Ok(())
+}
+
+async fn print_reports(state: &ServerState) -> anyhow::Result<()> {
+    let current = state.snapshots.current().await.version.to_string();
+    for stored in state.reports.list() {
+        let report = &stored.report;
+        let time = chrono::DateTime::<chrono::Local>::from(stored.received_at);
+        let failed = report
+            .items
+            .iter()
+            .filter(|a| a.outcome == ItemOutcome::Failed)
+            .count();
+        let version = report.manifest_version.as_deref().unwrap_or("-");
+        sprintln!(
+            "{} {} {} {}{} {} 失败 {}/{} 项 {} 字节 {}ms",
+            report.client_id,
+            stored.ip,
+            time.format("%Y-%m-%d %H:%M:%S"),
+            version,
+            if version == current { "" } else { " (旧版本)" },
+            if report.is_success() { "成功" } else { "失败" },
+            failed,
+            report.items.len(),
+            report.bytes,
+            report.duration_ms,
+        )?;
+    }
+    Ok(())
+}
+
+async fn print_clients(state: &ServerState) -> anyhow::Result<()> {
+    let current = state.snapshots.current().await.version.to_string();
+    for client in state.clients.list() {
+        let view = client.to_view(&current);
+        sprintln!(
+            "{} {} 最近访问 {} 客户端版本 {} 已同步 {}{} 失败 {} 次",
+            view.id,
+            view.ip,
+            view.last_seen,
+            view.client_version.as_deref().unwrap_or("-"),
+            view.synced_version.as_deref().unwrap_or("-"),
+            if view.outdated { " (旧版本)" } else { "" },
+            view.failures,
+        )?;
+    }
+    Ok(())
+}
+
+async fn print_ignored(state: &ServerState) -> anyhow::Result<()> {
+    let current = state.snapshots.current().await;
+    for path in &current.excluded {
+        sprintln!("{path}")?;
+    }
+    sprintln!("当前快照 {} 共忽略 {} 项", current.version, current.excluded.len())?;
+    Ok(())
+}
+
+async fn print_replicas(state: &ServerState) -> anyhow::Result<()> {
+    let Some(replicator) = &state.replicator else {
+        sprintln!("未配置从服务器")?;
+        return Ok(());
+    };
+    let current = state.snapshots.current().await.version;
+    let versions: Vec<_> = state.snapshots.list().iter().map(|a| a.version).collect();
+    for (url, status) in replicator.status() {
+        let lag = match status.version {
+            Some(version) if version == current => "已同步".to_string(),
+            version => {
+                // 落后的时间从当前版本发布时算起
+                let behind = versions
+                    .iter()
+                    .filter(|a| version.is_none_or(|v| **a > v))
+                    .count();
+                let since = chrono::Local::now()
+                    - chrono::DateTime::<chrono::Local>::from(current.datetime());
+                format!("落后 {behind} 个版本，{} 秒", since.num_seconds())
+            }
+        };
+        let last_push = status.last_push.map_or("-".to_string(), |a| {
+            chrono::DateTime::<chrono::Local>::from(a)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        });
+        sprintln!(
+            "{url} {} {lag} 上次推送 {last_push}{}",
+            status.version.map_or("-".to_string(), |a| a.to_string()),
+            status.error.map_or(String::new(), |e| format!(" 错误: {e}")),
+        )?;
+    }
+    Ok(())
+}
+
+/// `log` 列出当前级别，`log level <目标> <级别>` 修改级别
+fn set_log_level(state: &ServerState, arg: &str) -> anyhow::Result<()> {
+    let args: Vec<_> = arg.split_whitespace().collect();
+    match args[..] {
+        [] => {
+            let (root, targets) = state.log.levels();
+            sprintln!("root {root}")?;
+            for (target, level) in targets {
+                sprintln!("{target} {level}")?;
+            }
+        }
+        ["level", target, level] => match LevelFilter::from_str(level) {
+            Ok(level) => match state.log.set_level(target, level) {
+                Ok(()) => log::info!("Log level of {target} set to {level}"),
+                Err(e) => sprintln!("修改日志级别失败：{e}")?,
+            },
+            Err(_) => sprintln!("未知的日志级别 {level}，可用 off error warn info debug trace")?,
+        },
+        _ => sprintln!("用法: log level <目标> <级别>")?,
+    }
+    Ok(())
+}
+
 fn print_help() -> anyhow::Result<()> {
     sprintln!(
         r#"? | h | help 				=> 显示此帮助信息
-q | quit | exit | stop 			=> 退出进程
+q | quit | exit | stop 			=> 等待进行中的请求完成后退出进程
 r | reload 				=> 重新加载文件，重新生成清单
+l | list 				=> 列出保留的快照
+rollback <版本> 			=> 重新发布一个保留的快照
+reports 				=> 列出各客户端最近一次的同步结果
+clients 				=> 列出已知的客户端
+ban <IP 或 CIDR> 			=> 封禁地址，保存到 .bans
+unban <IP 或 CIDR> 			=> 解除封禁
+bans 					=> 列出封禁的地址
+ignored 				=> 列出当前快照发布时被忽略规则排除的文件
+replicas 				=> 列出从服务器的复制状态与延迟
+log 					=> 列出各日志目标的级别
+log level <目标> <级别> 		=> 修改日志级别，目标为 root 时修改默认级别
 
-在 content 文件夹内放置需要同步的文件，后缀为删除后缀表示要删除的文件（默认.del）"#
+在 content 文件夹内放置需要同步的文件，要删除的文件列在 content/syner-removed.toml 中（支持 glob）
+开启 remove_ext_marker 时，后缀为删除后缀的文件也表示要删除的文件（默认.del）
+content 文件夹内的 .synerignore（语法同 .gitignore）与配置中的 ignore 用于排除文件
+stdin 不是终端时控制台被禁用，可发送 SIGHUP 重新加载，SIGTERM / SIGINT 退出，
+rollback 等其他指令只能在控制台中使用"#
     )?;
     Ok(())
 }
@@ -192,9 +589,12 @@ r | reload 				=> 重新加载文件，重新生成清单
 async fn collect_manifest(
     config: Arc<Config>,
     content_path: Arc<PathBuf>,
+    changed: ChangedFiles,
+    tombstones: &Tombstones,
 ) -> anyhow::Result<Arc<ManifestData>> {
     let map = Arc::new(DashMap::new());
-    collect_manifest_files(config, content_path.clone(), content_path, map.clone()).await?;
+    collect_manifest_files(config, content_path.clone(), content_path, map.clone(), changed).await?;
+    tombstones.apply(&map)?;
     Ok(Arc::new(ManifestData {
         blob: rmp_serde::to_vec(&*map)?,
         data: map,
@@ -206,6 +606,7 @@ async fn collect_manifest_files(
     root_path: Arc<PathBuf>,
     dir: Arc<PathBuf>,
     map: Manifest,
+    changed: ChangedFiles,
 ) -> anyhow::Result<()> {
     let mut read_dir = tokio::fs::read_dir(&*dir).await?;
 
@@ -215,6 +616,7 @@ async fn collect_manifest_files(
         let config = config.clone();
         let map = map.clone();
         let root_path = root_path.clone();
+        let changed = changed.clone();
         set.spawn(async move {
             let entry = entry;
             let map = map;
@@ -225,10 +627,10 @@ async fn collect_manifest_files(
                 let root_path = root_path.clone();
                 let path = path.clone();
                 let map = map.clone();
-                fn f(config:Arc<Config>,root_path: Arc<PathBuf>, path: Arc<PathBuf>, map: Manifest) -> impl Future<Output = anyhow::Result<()>> + Send {
-                    collect_manifest_files(config,root_path, path, map)
+                fn f(config:Arc<Config>,root_path: Arc<PathBuf>, path: Arc<PathBuf>, map: Manifest, changed: ChangedFiles) -> impl Future<Output = anyhow::Result<()>> + Send {
+                    collect_manifest_files(config,root_path, path, map, changed)
                 }
-                tokio::task::spawn(f(config.clone(), root_path, path, map)).await??;
+                tokio::task::spawn(f(config.clone(), root_path, path, map, changed)).await??;
                 return Ok(());
             }
 
@@ -236,7 +638,7 @@ async fn collect_manifest_files(
                 return Ok(());
             }
 
-            let op = if let Some(true) = path.extension().map(|s| s.to_string_lossy() == config.remove_ext) {
+            let op = if config.remove_ext_marker && path.extension().is_some_and(|s| s.to_string_lossy() == config.remove_ext) {
                 ItemOp::Remove
             } else {
                 ItemOp::Sync
@@ -248,6 +650,13 @@ async fn collect_manifest_files(
 
             let hash = calc_hash(file).await?;
 
+            // 硬链接模式下快照与内容文件夹共享数据，原地修改仍会反映到快照中
+            let after = tokio::fs::metadata(&*path).await?;
+            if file_stamp(&meta) != file_stamp(&after) {
+                log::warn!(target: "manifest", "{:?} changed while hashing", path);
+                changed.lock().unwrap().push((*path).clone());
+            }
+
             let mut rel = pathdiff::diff_paths(&*path, &*root_path).unwrap();
             if let ItemOp::Remove = op {
                 rel = rel.file_stem().unwrap().into();
@@ -275,63 +684,227 @@ async fn collect_manifest_files(
     Ok(())
 }
 
-async fn re_collect_manifest(
+/// 先将内容固定到新的快照目录，再从快照生成清单
+async fn publish_snapshot(
     config: Arc<Config>,
     content_path: Arc<PathBuf>,
     backup_path: Arc<PathBuf>,
-    manifest: Arc<tokio::sync::RwLock<Arc<ManifestData>>>,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<Arc<Snapshot>> {
+    let version = Ulid::new();
+    let dir = Arc::new(backup_path.join(version.to_string()));
+    tokio::fs::create_dir_all(&*dir).await?;
+
+    let changed = ChangedFiles::default();
+    let excluded = ExcludedFiles::default();
+    let manifest = async {
+        // 被忽略的文件不会进入快照目录，因此也不会出现在清单中
+        let rules = IgnoreRules::new(&content_path, &config.ignore)?;
+        let tombstones = Tombstones::load(&content_path).await?;
+        backup_content(
+            config.clone(),
+            content_path.clone(),
+            dir.clone(),
+            (*content_path).clone(),
+            changed.clone(),
+            rules,
+            excluded.clone(),
+        )
+        .await?;
+        let manifest = collect_manifest(config, dir.clone(), changed.clone(), &tombstones).await?;
+
+        let changed = std::mem::take(&mut *changed.lock().unwrap());
+        if !changed.is_empty() {
+            anyhow::bail!("以下文件在发布过程中被修改，请稍后重试: {changed:?}");
+        }
+        let mut excluded = std::mem::take(&mut *excluded.lock().unwrap());
+        excluded.sort();
+        if !excluded.is_empty() {
+            log::info!(target: "manifest", "Excluded {} files by ignore rules", excluded.len());
+        }
+        let snapshot = Snapshot::new(version, (*dir).clone(), manifest, excluded);
+        snapshot.save_manifest().await?;
+        Ok(snapshot)
+    }
+    .await;
+
+    match manifest {
+        Ok(snapshot) => Ok(Arc::new(snapshot)),
+        Err(e) => {
+            let dir = (*dir).clone();
+            tokio::task::spawn_blocking(move || remove_dir_all::remove_dir_all(dir)).await??;
+            Err(e)
+        }
+    }
+}
+
+async fn re_collect_manifest(state: Arc<ServerState>) -> anyhow::Result<()> {
+    if state.config.replication.follower {
+        sprintln!("从服务器的版本由主服务器推送")?;
+        return Ok(());
+    }
+    if let Some(relay) = &state.relay {
+        sprintln!("正在从上游获取清单")?;
+        if refresh_relay(&state, relay).await? {
+            sprintln!("清单加载完成")?;
+        } else {
+            sprintln!("上游清单没有变化")?;
+        }
+        return Ok(());
+    }
     sprintln!("正在重新加载清单")?;
-    tokio::fs::create_dir_all(&*content_path).await?;
-    tokio::fs::create_dir_all(&*backup_path).await?;
-    let new = collect_manifest(config.clone(), content_path.clone()).await?;
-    let mut manifest = manifest.write().await;
-    ready_for_backup(backup_path.clone()).await?;
-    backup_content(config, content_path.clone(), backup_path, &*content_path).await?;
-    *manifest = new;
+    tokio::fs::create_dir_all(&*state.content_path).await?;
+    tokio::fs::create_dir_all(&*state.backup_path).await?;
+    let start = Instant::now();
+    let new = publish_snapshot(
+        state.config.clone(),
+        state.content_path.clone(),
+        state.backup_path.clone(),
+    )
+    .await;
+    let previous = state.snapshots.current().await;
+    let new = match new {
+        Ok(new) => before_publish(&state.config, Some(&previous), new).await,
+        Err(e) => Err(e),
+    };
+    // 写入文件库失败时快照已被删除，同样算作发布失败
+    let new = match new {
+        Ok(new) => state
+            .blobs
+            .publish(&state.snapshots, new.clone())
+            .await
+            .map(|_| new),
+        Err(e) => Err(e),
+    };
+    state.metrics.observe_reload(start.elapsed(), new.is_ok());
+    let new = new?;
+    state.metrics.retain(&new);
+    if let Some(replicator) = &state.replicator {
+        replicator.trigger();
+    }
+    after_publish(&state.config, Some(&previous), &new).await;
+    if let Some(updates) = &state.updates {
+        updates.reload(&state.blobs).await?;
+    }
     sprintln!("清单加载完成")?;
     Ok(())
 }
 
-async fn ready_for_backup(backup_path: Arc<PathBuf>) -> anyhow::Result<()> {
-    tokio::task::spawn_blocking(move || remove_dir_all::ensure_empty_dir(&*backup_path)).await??;
-    Ok(())
+/// 中继模式下以上游的清单作为快照，快照目录为空，文件按需从上游获取
+async fn relay_snapshot(
+    backup_path: &Path,
+    version: Ulid,
+    manifest: Arc<ManifestData>,
+) -> anyhow::Result<Arc<Snapshot>> {
+    let dir = backup_path.join(version.to_string());
+    tokio::fs::create_dir_all(&dir).await?;
+    let snapshot = Snapshot::new(version, dir, manifest, vec![]);
+    snapshot.save_manifest().await?;
+    Ok(Arc::new(snapshot))
+}
+
+/// 上游版本变化时切换到新的清单并清理缓存，返回是否切换
+async fn refresh_relay(state: &ServerState, relay: &Relay) -> anyhow::Result<bool> {
+    let start = Instant::now();
+    let fetched = relay.fetch_manifest().await;
+    state
+        .metrics
+        .observe_reload(start.elapsed(), fetched.is_ok());
+    let (version, manifest) = fetched?;
+    if version == state.snapshots.current().await.version {
+        return Ok(false);
+    }
+    let snapshot = relay_snapshot(&state.backup_path, version, manifest).await?;
+    state.snapshots.publish(snapshot.clone()).await;
+    state.metrics.retain(&snapshot);
+    relay.retain(&snapshot.manifest);
+    Ok(true)
+}
+
+/// 定期检查上游清单
+async fn relay_thread(state: Arc<ServerState>, relay: Arc<Relay>) {
+    let period = state.config.relay.poll_interval.max(Duration::from_secs(1));
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        match refresh_relay(&state, &relay).await {
+            Ok(true) => log::info!(target: "relay", "Switched to upstream manifest"),
+            Ok(false) => {}
+            Err(e) => log::warn!(target: "relay", "Refresh upstream manifest failed: {e:?}"),
+        }
+    }
 }
 
 async fn backup_content(
     config: Arc<Config>,
     content_path: Arc<PathBuf>,
     backup_path: Arc<PathBuf>,
-    cur_dir: &Path,
+    cur_dir: PathBuf,
+    changed: ChangedFiles,
+    rules: IgnoreRules,
+    excluded: ExcludedFiles,
 ) -> anyhow::Result<()> {
-    let mut read_dir = tokio::fs::read_dir(&*cur_dir).await?;
+    let rules = rules.enter(&cur_dir)?;
+    let mut read_dir = tokio::fs::read_dir(&cur_dir).await?;
     let mut set = JoinSet::<anyhow::Result<()>>::new();
 
     while let Some(entry) = read_dir.next_entry().await? {
         let config = config.clone();
         let content_path = content_path.clone();
         let backup_path = backup_path.clone();
+        let changed = changed.clone();
+        let rules = rules.clone();
+        let excluded = excluded.clone();
         set.spawn(async move {
             let path = entry.path();
             let rel = pathdiff::diff_paths(&path, &*content_path).unwrap();
             let mut dst = (*backup_path).clone();
-            dst.push(rel);
+            dst.push(&rel);
+
+            if rel.as_os_str() == TOMBSTONE_FILE {
+                return Ok(());
+            }
 
             let meta = entry.metadata().await?;
+            if rules.is_ignored(&path, meta.is_dir()) {
+                if entry.file_name() != IGNORE_FILE {
+                    let mut rel: Vec<_> = rel.iter().map(|s| s.to_string_lossy()).collect();
+                    if meta.is_dir() {
+                        rel.push("".into());
+                    }
+                    log::info!(target: "manifest", "Ignored {:?}", path);
+                    excluded.lock().unwrap().push(rel.join("/"));
+                }
+                return Ok(());
+            }
+
             if meta.is_dir() {
                 fn f(
                     config: Arc<Config>,
                     content_path: Arc<PathBuf>,
                     backup_path: Arc<PathBuf>,
                     cur_dir: PathBuf,
+                    changed: ChangedFiles,
+                    rules: IgnoreRules,
+                    excluded: ExcludedFiles,
                 ) -> impl Future<Output = anyhow::Result<()>> + Send {
-                    async move { backup_content(config, content_path, backup_path, &cur_dir).await }
+                    backup_content(
+                        config,
+                        content_path,
+                        backup_path,
+                        cur_dir,
+                        changed,
+                        rules,
+                        excluded,
+                    )
                 }
                 tokio::spawn(f(
                     config.clone(),
                     content_path.clone(),
                     backup_path.clone(),
                     path,
+                    changed,
+                    rules,
+                    excluded,
                 ))
                 .await??;
 
@@ -342,20 +915,20 @@ async fn backup_content(
                 return Ok(());
             }
 
-            if let Some(true) = path
-                .extension()
-                .map(|s| s.to_string_lossy() == config.remove_ext)
-            {
-                return Ok(());
-            }
-
             log::info!(target: "manifest", "Backup {:?} => {:?}", path, dst);
 
             let mut dst_dir = dst.clone();
             dst_dir.pop();
             tokio::fs::create_dir_all(&*dst_dir).await?;
 
-            tokio::fs::hard_link(path, dst).await?;
+            freeze_file(config.freeze_mode, &path, &dst).await?;
+
+            // 复制过程中被修改的文件，快照中可能是不完整的内容
+            let after = tokio::fs::metadata(&path).await?;
+            if file_stamp(&meta) != file_stamp(&after) {
+                log::warn!(target: "manifest", "{:?} changed while freezing", path);
+                changed.lock().unwrap().push(path);
+            }
 
             Ok(())
         });
@@ -368,34 +941,288 @@ async fn backup_content(
     Ok(())
 }
 
+async fn freeze_file(mode: FreezeMode, src: &Path, dst: &Path) -> anyhow::Result<()> {
+    match mode {
+        FreezeMode::HardLink => tokio::fs::hard_link(src, dst).await?,
+        FreezeMode::Reflink => {
+            let src = src.to_path_buf();
+            let dst = dst.to_path_buf();
+            tokio::task::spawn_blocking(move || reflink_copy::reflink_or_copy(src, dst)).await??;
+        }
+        FreezeMode::Copy => {
+            tokio::fs::copy(src, dst).await?;
+        }
+    }
+    Ok(())
+}
+
 async fn server_thread(
-    config: Arc<Config>,
-    manifest: Arc<tokio::sync::RwLock<Arc<ManifestData>>>,
+    state: Arc<ServerState>,
+    shutdown: tokio::sync::watch::Receiver<bool>,
 ) -> anyhow::Result<()> {
+    let config = state.config.clone();
     tokio::fs::create_dir_all(&config.content_path).await?;
 
-    let manifest = warp::get()
-        .and(warp::path("manifest"))
-        .and(log_req(true))
-        .and_then(move || get_manifest(manifest.clone()));
-    let contents = warp::path("content")
-        .and(warp::get().or(warp::head()))
-        .unify()
-        .and(log_req(true))
-        .and(warp::fs::dir(BACKUP_PATH));
-    let fallback = warp::any()
-        .and(log_req(false))
-        .map(|| StatusCode::IM_A_TEAPOT);
-
-    let routes = manifest.or(contents).or(fallback);
-    warp::serve(routes).run(config.server_addr).await;
+    let manifest = {
+        let state = state.clone();
+        warp::get()
+            .and(warp::path("manifest"))
+            .and(track_client(config.clone(), state.clients.clone()))
+            .and(warp::header::optional::<u32>(MANIFEST_FORMAT_HEADER))
+            .and_then(move |format| get_manifest(state.clone(), format))
+    };
+    let contents = {
+        let state = state.clone();
+        warp::path("content")
+            .and(warp::get().or(warp::head()))
+            .unify()
+            .and(track_client(config.clone(), state.clients.clone()))
+            .and(get_ip(config.clone()))
+            .and(warp::method())
+            .and(warp::path::tail())
+            .and(optional_header::<Range>())
+            .and_then(move |ci: ClientInfo, method, tail, range| {
+                let req = FileRequest {
+                    metrics: state.metrics.clone(),
+                    access: state.access.clone(),
+                    ip: ci.ip(),
+                    method,
+                    range,
+                };
+                get_content(state.snapshots.clone(), state.relay.clone(), req, tail)
+            })
+    };
+    let blob = {
+        let state = state.clone();
+        warp::path("blob")
+            .and(warp::get().or(warp::head()))
+            .unify()
+            .and(track_client(config.clone(), state.clients.clone()))
+            .and(get_ip(config.clone()))
+            .and(warp::method())
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .and(optional_header::<Range>())
+            .and(optional_header::<IfNoneMatch>())
+            .and_then(move |ci: ClientInfo, method, hash, range, if_none_match| {
+                let req = FileRequest {
+                    metrics: state.metrics.clone(),
+                    access: state.access.clone(),
+                    ip: ci.ip(),
+                    method,
+                    range,
+                };
+                get_blob(
+                    state.blobs.clone(),
+                    state.snapshots.clone(),
+                    state.relay.clone(),
+                    req,
+                    hash,
+                    if_none_match,
+                )
+            })
+    };
+    let report = {
+        let state = state.clone();
+        warp::post()
+            .and(warp::path("report"))
+            .and(warp::path::end())
+            .and(get_ip(config.clone()))
+            .and(warp::body::content_length_limit(16 * 1024 * 1024))
+            .and(warp::body::bytes())
+            .and_then(move |ci, body| post_report(state.clone(), ci, body))
+    };
+    let clients = {
+        let state = state.clone();
+        warp::get()
+            .and(warp::path("clients"))
+            .and(warp::path::end())
+            .and(warp::header::optional::<String>(AUTHORIZATION.as_str()))
+            .and_then(move |auth| get_clients(state.clone(), auth))
+    };
+    let peers = {
+        let state = state.clone();
+        warp::get()
+            .and(warp::path("peers"))
+            .and(warp::path::end())
+            .and(track_client(config.clone(), state.clients.clone()))
+            .and(warp::header::optional::<Uuid>(CLIENT_ID_HEADER))
+            .and_then(move |id| get_peers(state.clone(), id))
+    };
+    let metrics = {
+        let state = state.clone();
+        warp::get()
+            .and(warp::path("metrics"))
+            .and(warp::path::end())
+            .and_then(move || get_metrics(state.clone()))
+    };
+    let fallback = warp::any().map(|| StatusCode::IM_A_TEAPOT);
+
+    let routes = access_entry(config.clone())
+        .and(
+            access_filter(config.clone(), state.access.clone())
+                .and(
+                    manifest
+                        .or(contents)
+                        .or(blob)
+                        .or(report)
+                        .or(clients)
+                        .or(peers)
+                        .or(replication_routes(state.clone()))
+                        .or(metrics)
+                        .or(fallback),
+                )
+                .recover(handle_rejection),
+        )
+        .map(log_response)
+        .with(warp::log::custom({
+            let metrics = state.metrics.clone();
+            move |info| metrics.observe_request(info.path(), info.status().as_u16())
+        }));
+
+    tokio::spawn(prune_thread(state.access.clone()));
+    if let Some(relay) = &state.relay {
+        tokio::spawn(relay_thread(state.clone(), relay.clone()));
+    }
+    if let Some(replicator) = &state.replicator {
+        tokio::spawn(replication_thread(state.clone(), replicator.clone()));
+    }
+
+    let mut signal = shutdown.clone();
+    let (addr, server) =
+        warp::serve(routes).try_bind_with_graceful_shutdown(config.server_addr, async move {
+            let _ = signal.wait_for(|stop| *stop).await;
+        })?;
+    log::info!("Listening on {addr}");
+    let mut server = tokio::spawn(server);
+
+    let mut shutdown = shutdown;
+    tokio::select! {
+        r = &mut server => return Ok(r?),
+        _ = shutdown.wait_for(|stop| *stop) => {}
+    }
+
+    // 不再接受新连接，等待进行中的下载完成
+    log::info!(
+        "Waiting up to {} for in-flight requests",
+        humantime::format_duration(config.shutdown_timeout)
+    );
+    if tokio::time::timeout(config.shutdown_timeout, server)
+        .await
+        .is_err()
+    {
+        log::warn!("Shutdown timeout elapsed, dropping remaining connections");
+    }
 
     Ok(())
 }
 
+/// `format` 为客户端能处理的清单格式，旧客户端得到展开 glob 后的清单
 async fn get_manifest(
-    manifest: Arc<tokio::sync::RwLock<Arc<ManifestData>>>,
+    state: Arc<ServerState>,
+    format: Option<u32>,
+) -> Result<impl warp::Reply, Rejection> {
+    let snapshot = state.snapshots.current().await;
+    if snapshot.is_placeholder() {
+        return Ok(StatusCode::SERVICE_UNAVAILABLE.into_response());
+    }
+    let legacy = format.is_none_or(|a| a < 1);
+    if legacy {
+        if let Err(e) = snapshot.init_legacy(&state.snapshots.list()) {
+            log::error!(target: "manifest", "Expand globs for legacy clients failed: {e:?}");
+            return Err(warp::reject::reject());
+        }
+    }
+    Ok(ManifestReply {
+        snapshot,
+        legacy,
+        update: state.updates.as_ref().and_then(|a| a.header()),
+    }
+    .into_response())
+}
+
+async fn post_report(
+    state: Arc<ServerState>,
+    ci: ClientInfo,
+    body: warp::hyper::body::Bytes,
+) -> Result<impl warp::Reply, Rejection> {
+    let report: SyncReport = match rmp_serde::from_slice(&body) {
+        Ok(report) => report,
+        Err(e) => {
+            log::warn!(target: "report", "{} => invalid report: {e}", ci.ip());
+            return Ok(StatusCode::BAD_REQUEST);
+        }
+    };
+    log::info!(
+        target: "report",
+        "{} => client {} synced {:?} {} in {}ms",
+        ci.ip(),
+        report.client_id,
+        report.manifest_version,
+        if report.is_success() { "successfully" } else { "with errors" },
+        report.duration_ms
+    );
+    if let Err(e) = state.clients.record_report(ci.ip(), &report).await {
+        log::error!(target: "client", "Save client registry failed: {e:?}");
+    }
+    match state.reports.insert(ci.ip(), report).await {
+        Ok(_) => Ok(StatusCode::NO_CONTENT),
+        Err(e) => {
+            log::error!(target: "report", "Save report failed: {e:?}");
+            Ok(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// 列表中有每个客户端的 IP，需要携带复制密钥，未设置密钥时只能在控制台中查看
+async fn get_clients(
+    state: Arc<ServerState>,
+    auth: Option<String>,
+) -> Result<warp::reply::Response, Rejection> {
+    let token = &state.config.replication.token;
+    if token.is_empty() {
+        return Err(warp::reject::not_found());
+    }
+    if !token_matches(token, auth.as_deref()) {
+        log::warn!(target: "client", "Rejected client list request with invalid token");
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+    let current = state.snapshots.current().await.version.to_string();
+    let clients: Vec<_> = state
+        .clients
+        .list()
+        .iter()
+        .map(|a| a.to_view(&current))
+        .collect();
+    Ok(warp::reply::json(&clients).into_response())
+}
+
+/// 同一局域网中可以下载文件的其他客户端，msgpack 编码的地址列表
+async fn get_peers(
+    state: Arc<ServerState>,
+    id: Option<Uuid>,
 ) -> Result<impl warp::Reply, Rejection> {
-    let manifest = manifest.read().await;
-    Ok(ManifestReply(manifest.clone()))
+    let peers = state.clients.peers(id, state.config.peer_ttl);
+    let blob = rmp_serde::to_vec(&peers).map_err(|_| warp::reject::reject())?;
+    Ok(warp::reply::with_header(
+        blob,
+        CONTENT_TYPE,
+        "application/msgpack",
+    ))
+}
+
+async fn get_metrics(state: Arc<ServerState>) -> Result<impl warp::Reply, Rejection> {
+    let current = state.snapshots.current().await;
+    match state.metrics.render(&current) {
+        Ok(text) => Ok(warp::reply::with_header(
+            text,
+            "content-type",
+            prometheus::TEXT_FORMAT,
+        )
+        .into_response()),
+        Err(e) => {
+            log::error!("Render metrics failed: {e:?}");
+            Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
 }
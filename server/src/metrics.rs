@@ -0,0 +1,160 @@
+use std::time::Duration;
+
+use dashmap::DashMap;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+use crate::snapshot::Snapshot;
+
+/// 请求按路径的第一段统计，其余路径计入 `other`
+const ROUTES: &[&str] = &[
+    "manifest",
+    "content",
+    "blob",
+    "report",
+    "clients",
+    "peers",
+    "metrics",
+    "replication",
+];
+
+/// 服务器运行指标，以 Prometheus 文本格式通过 `/metrics` 导出
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    bytes_served: IntCounter,
+    active_downloads: IntGauge,
+    reload_duration: Histogram,
+    reload_failures: IntCounter,
+    manifest_bytes: IntGauge,
+    manifest_files: IntGauge,
+    top_files: IntGaugeVec,
+    top_files_limit: usize,
+    /// 每个文件的下载次数，只导出次数最多的若干个，避免标签数量无限增长。
+    /// 发布新版本后只保留当前清单中的文件
+    file_downloads: DashMap<String, u64>,
+}
+
+impl Metrics {
+    pub fn new(top_files_limit: usize) -> anyhow::Result<Self> {
+        let registry = Registry::new_custom(Some("syner".into()), None)?;
+
+        let requests = IntCounterVec::new(
+            Opts::new("requests_total", "HTTP requests by route and status"),
+            &["route", "status"],
+        )?;
+        let bytes_served = IntCounter::new("bytes_served_total", "Content bytes sent")?;
+        let active_downloads =
+            IntGauge::new("active_downloads", "Content downloads in progress")?;
+        let reload_duration = Histogram::with_opts(
+            HistogramOpts::new("reload_duration_seconds", "Time spent publishing a snapshot")
+                .buckets(vec![0.1, 0.5, 1.0, 5.0, 15.0, 30.0, 60.0, 300.0, 900.0]),
+        )?;
+        let reload_failures = IntCounter::new("reload_failures_total", "Failed publishes")?;
+        let manifest_bytes =
+            IntGauge::new("manifest_bytes", "Size of the current manifest in bytes")?;
+        let manifest_files =
+            IntGauge::new("manifest_files", "Entries in the current manifest")?;
+        let top_files = IntGaugeVec::new(
+            Opts::new("file_downloads", "Downloads of the most requested files"),
+            &["path"],
+        )?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(bytes_served.clone()))?;
+        registry.register(Box::new(active_downloads.clone()))?;
+        registry.register(Box::new(reload_duration.clone()))?;
+        registry.register(Box::new(reload_failures.clone()))?;
+        registry.register(Box::new(manifest_bytes.clone()))?;
+        registry.register(Box::new(manifest_files.clone()))?;
+        registry.register(Box::new(top_files.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests,
+            bytes_served,
+            active_downloads,
+            reload_duration,
+            reload_failures,
+            manifest_bytes,
+            manifest_files,
+            top_files,
+            top_files_limit,
+            file_downloads: DashMap::new(),
+        })
+    }
+
+    pub fn observe_request(&self, path: &str, status: u16) {
+        let route = path
+            .trim_start_matches('/')
+            .split('/')
+            .next()
+            .filter(|route| ROUTES.contains(route))
+            .unwrap_or("other");
+        self.requests
+            .with_label_values(&[route, &status.to_string()])
+            .inc();
+    }
+
+    pub fn observe_reload(&self, duration: Duration, success: bool) {
+        self.reload_duration.observe(duration.as_secs_f64());
+        if !success {
+            self.reload_failures.inc();
+        }
+    }
+
+    /// 开始一次下载，返回的守卫在传输结束（或连接断开）时释放
+    pub fn start_download(self: &std::sync::Arc<Self>, path: &str) -> DownloadGuard {
+        *self.file_downloads.entry(path.to_string()).or_default() += 1;
+        self.active_downloads.inc();
+        DownloadGuard {
+            metrics: self.clone(),
+        }
+    }
+
+    /// 删除不在当前清单中的文件的下载次数
+    pub fn retain(&self, current: &Snapshot) {
+        self.file_downloads.retain(|name, _| match name.strip_prefix("blob/") {
+            Some(hash) => current.references(hash),
+            None => current.manifest.data.contains_key(name),
+        });
+    }
+
+    pub fn render(&self, current: &Snapshot) -> anyhow::Result<String> {
+        self.manifest_bytes.set(current.manifest.blob.len() as i64);
+        self.manifest_files.set(current.manifest.data.len() as i64);
+
+        let mut downloads: Vec<_> = self
+            .file_downloads
+            .iter()
+            .map(|item| (item.key().clone(), *item.value()))
+            .collect();
+        downloads.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        self.top_files.reset();
+        for (path, count) in downloads.into_iter().take(self.top_files_limit) {
+            self.top_files.with_label_values(&[&path]).set(count as i64);
+        }
+
+        let mut buf = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(String::from_utf8(buf)?)
+    }
+}
+
+pub struct DownloadGuard {
+    metrics: std::sync::Arc<Metrics>,
+}
+
+impl DownloadGuard {
+    pub fn sent(&self, len: usize) {
+        self.metrics.bytes_served.inc_by(len as u64);
+    }
+}
+
+impl Drop for DownloadGuard {
+    fn drop(&mut self) {
+        self.metrics.active_downloads.dec();
+    }
+}
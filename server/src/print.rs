@@ -16,7 +16,10 @@ pub fn sprint(str: impl AsRef<str>) -> anyhow::Result<()> {
 
 #[cfg(not(target_os = "windows"))]
 pub fn sprint(str: impl AsRef<str>) -> anyhow::Result<()> {
-    std::io::stdout().write(str.as_ref().as_bytes())?;
+    use std::io::Write;
+
+    std::io::stdout().write_all(str.as_ref().as_bytes())?;
+    std::io::stdout().flush()?;
     Ok(())
 }
 
@@ -32,6 +35,7 @@ pub fn sprintf(fmt: std::fmt::Arguments<'_>) -> anyhow::Result<()> {
     use std::io::Write;
 
     std::io::stdout().write_fmt(fmt)?;
+    std::io::stdout().flush()?;
     Ok(())
 }
 
@@ -48,7 +52,7 @@ pub fn sprintfln(fmt: std::fmt::Arguments<'_>) -> anyhow::Result<()> {
     use std::io::Write;
 
     std::io::stdout().write_fmt(fmt)?;
-    std::io::stdout().write("\n".as_bytes())?;
+    std::io::stdout().write_all("\n".as_bytes())?;
     Ok(())
 }
 
@@ -117,8 +121,15 @@ pub fn s_read_line(str: &mut String) -> anyhow::Result<usize> {
 #[cfg(not(target_os = "windows"))]
 pub fn s_read_line(str: &mut String) -> anyhow::Result<usize> {
     let r = std::io::stdin().read_line(str)?;
-    Ok(r)
+    if r == 0 {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    let trimmed = str.trim_end_matches(['\r', '\n']).len();
+    str.truncate(trimmed);
+    Ok(str.len())
 }
+
+/// 读取一行输入，不包含换行符；stdin 关闭时返回 `UnexpectedEof`
 pub fn read_line() -> anyhow::Result<String> {
     let mut str = String::new();
     let len = s_read_line(&mut str)?;
@@ -128,3 +139,15 @@ pub fn read_line() -> anyhow::Result<String> {
         Ok(String::from_utf8_unchecked(vec))
     }
 }
+
+/// stdin 是否连接到终端；作为守护进程运行时控制台被禁用
+pub fn has_console() -> bool {
+    use std::io::IsTerminal;
+
+    std::io::stdin().is_terminal()
+}
+
+pub fn is_eof(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof)
+}
@@ -0,0 +1,296 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use dashmap::DashMap;
+use futures_util::StreamExt;
+use model::{ItemOp, MANIFEST_FORMAT, MANIFEST_FORMAT_HEADER, VERSION_HEADER};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use tokio::io::AsyncWriteExt;
+use ulid::Ulid;
+use url::Url;
+use warp::http::{Request, StatusCode};
+use warp::hyper::Body;
+
+use crate::blobs::BlobStore;
+use crate::server_model::ManifestData;
+use crate::utils::{http_client, HttpClient};
+
+/// 请求上游 `/content` 时需要转义的字符，保留 `/`
+const PATH_SET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RelayConfig {
+    /// 上游服务器地址，设置后以中继模式运行，不再读取 `content_path`，支持 http 与 https
+    pub upstream: Option<Url>,
+    /// 从上游下载的文件的缓存文件夹
+    pub cache_dir: PathBuf,
+    /// 缓存大小上限（MB），超出时删除最久未访问的文件，0 表示不限制
+    pub max_cache_mb: u64,
+    /// 检查上游清单版本的间隔
+    #[serde(with = "crate::utils::humantime_duration")]
+    pub poll_interval: Duration,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            upstream: None,
+            cache_dir: "./.relay".into(),
+            max_cache_mb: 10 * 1024,
+            poll_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// 中继模式：清单来自上游服务器，文件在第一次被请求时从上游下载并缓存
+///
+/// 缓存按内容哈希存放，与 `/blob` 共用同一目录
+pub struct Relay {
+    upstream: Url,
+    max_cache: u64,
+    cache: Arc<BlobStore>,
+    client: HttpClient,
+    /// 已缓存的文件：哈希 → (大小, 最近访问时间)
+    ///
+    /// 文件在持有此锁时删除和打开，记录存在时文件一定可以打开
+    entries: Mutex<HashMap<String, (u64, SystemTime)>>,
+    /// 同一文件只从上游下载一次
+    fetching: DashMap<String, Arc<tokio::sync::Mutex<()>>>,
+}
+
+impl Relay {
+    /// 读取缓存文件夹中已有的文件，以修改时间作为最近访问时间
+    pub async fn load(config: &RelayConfig, upstream: Url) -> anyhow::Result<Self> {
+        if !matches!(upstream.scheme(), "http" | "https") {
+            anyhow::bail!("不支持的上游地址 {upstream}，只支持 http 与 https");
+        }
+        let cache = Arc::new(BlobStore::open(config.cache_dir.clone(), false).await?);
+        let mut entries = HashMap::new();
+        let mut read_dir = tokio::fs::read_dir(&config.cache_dir).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            if !entry.file_type().await?.is_dir() {
+                continue;
+            }
+            let mut files = tokio::fs::read_dir(entry.path()).await?;
+            while let Some(file) = files.next_entry().await? {
+                let name = file.file_name().to_string_lossy().to_string();
+                if cache.path(&name).is_none() {
+                    // 上次运行中断时留下的临时文件
+                    tokio::fs::remove_file(file.path()).await?;
+                    continue;
+                }
+                let meta = file.metadata().await?;
+                let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                entries.insert(name, (meta.len(), modified));
+            }
+        }
+        log::info!(target: "relay", "Relay to {upstream}, {} files cached", entries.len());
+
+        Ok(Self {
+            upstream,
+            max_cache: config.max_cache_mb * 1024 * 1024,
+            cache,
+            client: http_client(),
+            entries: Mutex::new(entries),
+            fetching: DashMap::new(),
+        })
+    }
+
+    pub fn cache(&self) -> Arc<BlobStore> {
+        self.cache.clone()
+    }
+
+    /// 获取上游当前的清单及其版本
+    pub async fn fetch_manifest(&self) -> anyhow::Result<(Ulid, Arc<ManifestData>)> {
+        let res = self.get(self.upstream.join("manifest")?).await?;
+        if !res.status().is_success() {
+            anyhow::bail!("上游返回 {}", res.status());
+        }
+        let version = res
+            .headers()
+            .get(VERSION_HEADER)
+            .and_then(|a| a.to_str().ok())
+            .and_then(|a| Ulid::from_string(a).ok())
+            .ok_or_else(|| anyhow::anyhow!("上游清单没有版本"))?;
+        let blob = warp::hyper::body::to_bytes(res.into_body()).await?.to_vec();
+        let data: DashMap<_, _> = rmp_serde::from_slice(&blob)?;
+        Ok((
+            version,
+            Arc::new(ManifestData {
+                blob,
+                data: Arc::new(data),
+            }),
+        ))
+    }
+
+    /// 打开缓存中的文件，不存在时从上游下载，`path` 为空时只按哈希下载
+    ///
+    /// 返回已打开的文件，之后被淘汰或失效也不影响进行中的下载
+    pub async fn fetch(&self, hash: &str, path: Option<&str>) -> anyhow::Result<tokio::fs::File> {
+        let dst = self
+            .cache
+            .path(hash)
+            .ok_or_else(|| anyhow::anyhow!("无效的哈希 {hash}"))?;
+        if let Some(file) = self.open(hash, &dst)? {
+            return Ok(file);
+        }
+
+        let lock = self.fetching.entry(hash.to_string()).or_default().clone();
+        let _lock = lock.lock().await;
+        if let Some(file) = self.open(hash, &dst)? {
+            return Ok(file);
+        }
+        let result = self.download(hash, path, &dst).await.and_then(|len| {
+            let mut entries = self.entries.lock().unwrap();
+            let file = std::fs::File::open(&dst)?;
+            entries.insert(hash.to_string(), (len, SystemTime::now()));
+            Ok(file)
+        });
+        self.fetching.remove(hash);
+        let file = result?;
+        self.evict(hash);
+        Ok(tokio::fs::File::from_std(file))
+    }
+
+    /// 已缓存时更新最近访问时间并打开文件
+    fn open(&self, hash: &str, dst: &Path) -> anyhow::Result<Option<tokio::fs::File>> {
+        let mut entries = self.entries.lock().unwrap();
+        let Some(entry) = entries.get_mut(hash) else {
+            return Ok(None);
+        };
+        entry.1 = SystemTime::now();
+        Ok(Some(tokio::fs::File::from_std(std::fs::File::open(dst)?)))
+    }
+
+    /// 优先下载上游的 `/blob`，上游不支持时按路径下载，哈希不一致时不写入缓存
+    async fn download(&self, hash: &str, path: Option<&str>, dst: &Path) -> anyhow::Result<u64> {
+        let mut res = self
+            .get(self.upstream.join(&format!("blob/{hash}"))?)
+            .await?;
+        if res.status().is_client_error() {
+            if let Some(path) = path {
+                let path = utf8_percent_encode(path, PATH_SET);
+                res = self
+                    .get(self.upstream.join(&format!("content/{path}"))?)
+                    .await?;
+            }
+        }
+        if res.status() != StatusCode::OK {
+            anyhow::bail!("上游返回 {}", res.status());
+        }
+
+        if let Some(dir) = dst.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let tmp = dst.with_extension("tmp");
+        let mut file = tokio::fs::File::create(&tmp).await?;
+        let mut hasher = Sha3_256::new();
+        let mut len = 0;
+        let mut body = res.into_body();
+        let written = async {
+            while let Some(chunk) = body.next().await {
+                let chunk = chunk?;
+                hasher.update(&chunk);
+                file.write_all(&chunk).await?;
+                len += chunk.len() as u64;
+            }
+            file.flush().await?;
+            anyhow::Result::<_>::Ok(())
+        }
+        .await;
+        drop(file);
+
+        let actual = base16ct::lower::encode_string(&hasher.finalize());
+        let verified = written.and_then(|_| match actual == hash {
+            true => Ok(()),
+            false => Err(anyhow::anyhow!("上游文件哈希不一致 {actual}")),
+        });
+        if let Err(e) = verified {
+            tokio::fs::remove_file(&tmp).await?;
+            return Err(e);
+        }
+        tokio::fs::rename(&tmp, dst).await?;
+        log::info!(target: "relay", "Cached {hash} ({len} bytes)");
+        Ok(len)
+    }
+
+    async fn get(&self, url: Url) -> anyhow::Result<warp::hyper::Response<Body>> {
+        // 中继需要完整的清单，旧客户端使用的清单由中继自己展开
+        let req = Request::get(url.as_str())
+            .header(MANIFEST_FORMAT_HEADER, MANIFEST_FORMAT)
+            .body(Body::empty())?;
+        Ok(self.client.request(req).await?)
+    }
+
+    /// 上游版本变化后删除新清单中不再引用的文件
+    pub fn retain(&self, manifest: &ManifestData) {
+        let referenced: HashSet<String> = manifest
+            .data
+            .iter()
+            .filter(|a| a.value().0 == ItemOp::Sync)
+            .map(|a| base16ct::lower::encode_string(&a.value().2))
+            .collect();
+        let mut entries = self.entries.lock().unwrap();
+        let stale = entries
+            .keys()
+            .filter(|a| !referenced.contains(*a))
+            .cloned()
+            .collect::<Vec<_>>();
+        for hash in &stale {
+            entries.remove(hash);
+            self.remove(hash);
+        }
+        if !stale.is_empty() {
+            log::info!(target: "relay", "Invalidated {} cached files", stale.len());
+        }
+    }
+
+    /// 缓存超出上限时删除最久未访问的文件，刚下载的文件除外
+    fn evict(&self, keep: &str) {
+        if self.max_cache == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let mut total: u64 = entries.values().map(|a| a.0).sum();
+        let mut lru: Vec<_> = entries
+            .iter()
+            .filter(|a| a.0 != keep)
+            .map(|(hash, (len, last))| (*last, hash.clone(), *len))
+            .collect();
+        lru.sort();
+        for (_, hash, len) in lru {
+            if total <= self.max_cache {
+                break;
+            }
+            entries.remove(&hash);
+            self.remove(&hash);
+            total -= len;
+        }
+    }
+
+    /// 调用方持有 `entries` 的锁
+    fn remove(&self, hash: &str) {
+        let Some(path) = self.cache.path(hash) else {
+            return;
+        };
+        // 进行中的下载持有文件句柄时可能删除失败，重启后会重新计入缓存
+        if let Err(e) = std::fs::remove_file(&path) {
+            log::warn!(target: "relay", "Remove cached {hash} failed: {e}");
+        }
+    }
+}
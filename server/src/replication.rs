@@ -0,0 +1,424 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use dashmap::DashMap;
+use futures_util::{Stream, StreamExt};
+use model::ItemOp;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+use ulid::Ulid;
+use url::Url;
+use warp::http::{header::*, Request, StatusCode};
+use warp::hyper::body::{Buf, Bytes};
+use warp::hyper::Body;
+use warp::reject::Rejection;
+use warp::reply::{Reply, Response};
+use warp::Filter;
+
+use crate::access::{handle_rejection, Forbidden};
+use crate::blobs::{link_file, store_file, BlobStore};
+use crate::server_model::{Config, ManifestData, ServerState};
+use crate::snapshot::Snapshot;
+use crate::utils::{http_client, HttpClient};
+
+/// 推送失败后重试的间隔
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReplicationConfig {
+    /// 主从服务器共用的密钥，为空时不开启复制。查看 `/clients` 也需要携带这个密钥
+    pub token: String,
+    /// 主服务器：每次发布后把新版本推送到这些从服务器
+    pub followers: Vec<Url>,
+    /// 从服务器：只提供主服务器推送的版本，启动时不从 `content_path` 发布
+    pub follower: bool,
+}
+
+/// 主服务器记录的从服务器状态
+#[derive(Debug, Clone, Default)]
+pub struct FollowerStatus {
+    /// 从服务器确认切换到的版本
+    pub version: Option<Ulid>,
+    pub last_push: Option<SystemTime>,
+    pub error: Option<String>,
+}
+
+/// 主服务器：把当前版本推送到落后的从服务器
+pub struct Replicator {
+    token: String,
+    client: HttpClient,
+    followers: Vec<(Url, Mutex<FollowerStatus>)>,
+    notify: tokio::sync::Notify,
+}
+
+impl Replicator {
+    pub fn new(config: &ReplicationConfig) -> Self {
+        for follower in &config.followers {
+            if follower.scheme() != "https" {
+                log::warn!(target: "replication", "Follower {follower} is not https, the token is sent in plaintext");
+            }
+        }
+        Self {
+            token: config.token.clone(),
+            client: http_client(),
+            followers: config
+                .followers
+                .iter()
+                .map(|a| (a.clone(), Mutex::new(FollowerStatus::default())))
+                .collect(),
+            notify: tokio::sync::Notify::new(),
+        }
+    }
+
+    /// 发布或回滚后立即推送
+    pub fn trigger(&self) {
+        self.notify.notify_one();
+    }
+
+    pub fn status(&self) -> Vec<(Url, FollowerStatus)> {
+        self.followers
+            .iter()
+            .map(|(url, status)| (url.clone(), status.lock().unwrap().clone()))
+            .collect()
+    }
+
+    /// 依次询问缺少的文件、上传文件、提交清单，从服务器收到全部文件后才切换版本
+    async fn push(
+        &self,
+        follower: &Url,
+        snapshot: &Snapshot,
+        blobs: &BlobStore,
+    ) -> anyhow::Result<()> {
+        let hashes: BTreeSet<String> = snapshot
+            .manifest
+            .data
+            .iter()
+            .filter(|a| a.value().0 == ItemOp::Sync)
+            .map(|a| base16ct::lower::encode_string(&a.value().2))
+            .collect();
+        let hashes: Vec<String> = hashes.into_iter().collect();
+
+        let res = self
+            .send(
+                follower.join("replication/missing")?,
+                "POST",
+                Body::from(rmp_serde::to_vec(&hashes)?),
+                None,
+            )
+            .await?;
+        let missing: Vec<String> =
+            rmp_serde::from_slice(&warp::hyper::body::to_bytes(res.into_body()).await?)?;
+
+        let mut bytes = 0;
+        for hash in &missing {
+            let path = blobs
+                .path(hash)
+                .ok_or_else(|| anyhow::anyhow!("无效的哈希 {hash}"))?;
+            let file = tokio::fs::File::open(&path).await?;
+            let len = file.metadata().await?.len();
+            let body = Body::wrap_stream(ReaderStream::new(file));
+            self.send(
+                follower.join(&format!("replication/blob/{hash}"))?,
+                "PUT",
+                body,
+                Some(len),
+            )
+            .await?;
+            bytes += len;
+        }
+
+        self.send(
+            follower.join(&format!("replication/commit/{}", snapshot.version))?,
+            "POST",
+            Body::from(snapshot.manifest.blob.clone()),
+            None,
+        )
+        .await?;
+        log::info!(target: "replication", "Pushed {} to {follower}, {} of {} files ({bytes} bytes)", snapshot.version, missing.len(), hashes.len());
+        Ok(())
+    }
+
+    async fn send(
+        &self,
+        url: Url,
+        method: &str,
+        body: Body,
+        len: Option<u64>,
+    ) -> anyhow::Result<warp::hyper::Response<Body>> {
+        let mut req = Request::builder()
+            .method(method)
+            .uri(url.as_str())
+            .header(AUTHORIZATION, format!("Bearer {}", self.token));
+        if let Some(len) = len {
+            req = req.header(CONTENT_LENGTH, len);
+        }
+        let res = self.client.request(req.body(body)?).await?;
+        if !res.status().is_success() {
+            let status = res.status();
+            let body = warp::hyper::body::to_bytes(res.into_body()).await?;
+            anyhow::bail!("{url} 返回 {status} {}", String::from_utf8_lossy(&body));
+        }
+        Ok(res)
+    }
+}
+
+/// 等待发布通知或定时重试，把当前版本推送到所有落后的从服务器
+pub async fn replication_thread(state: Arc<ServerState>, replicator: Arc<Replicator>) {
+    loop {
+        let current = state.snapshots.current().await;
+        let pushes = replicator.followers.iter().map(|(follower, status)| {
+            let current = current.clone();
+            let state = state.clone();
+            let replicator = replicator.clone();
+            async move {
+                if status.lock().unwrap().version == Some(current.version) {
+                    return;
+                }
+                let result = replicator.push(follower, &current, &state.blobs).await;
+                let mut status = status.lock().unwrap();
+                status.last_push = Some(SystemTime::now());
+                match result {
+                    Ok(()) => {
+                        status.version = Some(current.version);
+                        status.error = None;
+                    }
+                    Err(e) => {
+                        log::warn!(target: "replication", "Push {} to {follower} failed: {e:?}", current.version);
+                        status.error = Some(format!("{e}"));
+                    }
+                }
+            }
+        });
+        futures_util::future::join_all(pushes).await;
+        let _ = tokio::time::timeout(RETRY_INTERVAL, replicator.notify.notified()).await;
+    }
+}
+
+/// 从服务器：校验主服务器的密钥，未开启时表现为不存在的路径
+fn authorized(config: Arc<Config>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>(AUTHORIZATION.as_str())
+        .and_then(move |auth: Option<String>| {
+            let config = config.clone();
+            async move {
+                let replication = &config.replication;
+                if !replication.follower || replication.token.is_empty() {
+                    return Err(warp::reject::not_found());
+                }
+                if token_matches(&replication.token, auth.as_deref()) {
+                    Ok(())
+                } else {
+                    log::warn!(target: "replication", "Rejected replication request with invalid token");
+                    Err(warp::reject::custom(Forbidden))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// `Authorization` 头是否为 `Bearer <token>`
+pub fn token_matches(token: &str, auth: Option<&str>) -> bool {
+    auth.and_then(|a| a.strip_prefix("Bearer "))
+        .is_some_and(|a| constant_eq(a.as_bytes(), token.as_bytes()))
+}
+
+fn constant_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// 从服务器接收推送的接口
+pub fn replication_routes(
+    state: Arc<ServerState>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let base = warp::path("replication").and(authorized(state.config.clone()));
+    let missing = {
+        let state = state.clone();
+        warp::post()
+            .and(warp::path("missing"))
+            .and(warp::path::end())
+            .and(warp::body::bytes())
+            .and_then(move |body| post_missing(state.clone(), body))
+    };
+    let blob = {
+        let state = state.clone();
+        warp::put()
+            .and(warp::path("blob"))
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .and(warp::body::stream())
+            .and_then(move |hash, body| put_blob(state.clone(), hash, body))
+    };
+    let commit = {
+        let state = state.clone();
+        warp::post()
+            .and(warp::path("commit"))
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .and(warp::body::bytes())
+            .and_then(move |version, body| post_commit(state.clone(), version, body))
+    };
+    // 外层的 fallback 会吞掉拒绝，密钥错误需要在这里转成 403
+    base.and(missing.or(blob).unify().or(commit).unify())
+        .recover(handle_rejection)
+        .unify()
+}
+
+/// 返回从服务器还没有的文件
+async fn post_missing(state: Arc<ServerState>, body: Bytes) -> Result<Response, Rejection> {
+    let Ok(hashes) = rmp_serde::from_slice::<Vec<String>>(&body) else {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+    let mut missing = vec![];
+    for hash in hashes {
+        let Some(path) = state.blobs.path(&hash) else {
+            return Ok(StatusCode::BAD_REQUEST.into_response());
+        };
+        if !tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            missing.push(hash);
+        }
+    }
+    Ok(rmp_serde::to_vec(&missing).unwrap().into_response())
+}
+
+/// 接收一个文件，哈希不一致时丢弃
+async fn put_blob(
+    state: Arc<ServerState>,
+    hash: String,
+    body: impl Stream<Item = Result<impl Buf, warp::Error>> + Unpin,
+) -> Result<Response, Rejection> {
+    let Some(dst) = state.blobs.path(&hash) else {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+    match receive_blob(&hash, &dst, body).await {
+        Ok(()) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Err(e) => {
+            log::warn!(target: "replication", "Receive {hash} failed: {e:?}");
+            Ok(warp::reply::with_status(format!("{e}"), StatusCode::BAD_REQUEST).into_response())
+        }
+    }
+}
+
+async fn receive_blob(
+    hash: &str,
+    dst: &std::path::Path,
+    mut body: impl Stream<Item = Result<impl Buf, warp::Error>> + Unpin,
+) -> anyhow::Result<()> {
+    if let Some(dir) = dst.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let tmp = dst.with_extension("tmp");
+    let mut file = tokio::fs::File::create(&tmp).await?;
+    let mut hasher = Sha3_256::new();
+    let written = async {
+        while let Some(chunk) = body.next().await {
+            let mut chunk = chunk?;
+            while chunk.has_remaining() {
+                let bytes = chunk.chunk();
+                hasher.update(bytes);
+                file.write_all(bytes).await?;
+                let len = bytes.len();
+                chunk.advance(len);
+            }
+        }
+        file.flush().await?;
+        anyhow::Result::<_>::Ok(())
+    }
+    .await;
+    drop(file);
+
+    let actual = base16ct::lower::encode_string(&hasher.finalize());
+    let verified = written.and_then(|_| match actual == hash {
+        true => Ok(()),
+        false => Err(anyhow::anyhow!("哈希不一致 {actual}")),
+    });
+    if let Err(e) = verified {
+        tokio::fs::remove_file(&tmp).await?;
+        return Err(e);
+    }
+    tokio::fs::rename(&tmp, dst).await?;
+    Ok(())
+}
+
+/// 所有文件都已收到时切换到推送的版本
+async fn post_commit(
+    state: Arc<ServerState>,
+    version: String,
+    body: Bytes,
+) -> Result<Response, Rejection> {
+    let Ok(version) = Ulid::from_string(&version) else {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+    match commit(&state, version, body.to_vec()).await {
+        Ok(()) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Err(e) => {
+            log::warn!(target: "replication", "Commit {version} failed: {e:?}");
+            Ok(warp::reply::with_status(format!("{e}"), StatusCode::CONFLICT).into_response())
+        }
+    }
+}
+
+async fn commit(state: &ServerState, version: Ulid, blob: Vec<u8>) -> anyhow::Result<()> {
+    if state.snapshots.current().await.version == version {
+        return Ok(());
+    }
+    if state.snapshots.list().iter().any(|a| a.version == version) {
+        return state.snapshots.rollback(version).await;
+    }
+
+    let data: DashMap<String, model::ManifestItem> = rmp_serde::from_slice(&blob)?;
+    let mut files = HashMap::new();
+    for item in data.iter() {
+        if item.value().0 != ItemOp::Sync {
+            continue;
+        }
+        let rel = item.key();
+        if rel
+            .split('/')
+            .any(|seg| seg.is_empty() || seg.starts_with("..") || seg.contains('\\'))
+        {
+            anyhow::bail!("无效的路径 {rel:?}");
+        }
+        let hash = base16ct::lower::encode_string(&item.value().2);
+        let src = state
+            .blobs
+            .path(&hash)
+            .filter(|a| a.is_file())
+            .ok_or_else(|| anyhow::anyhow!("缺少文件 {rel:?} ({hash})"))?;
+        files.insert(rel.clone(), src);
+    }
+
+    // 与发布一样先把文件固定到快照目录，文件库中的文件不会改变，可以直接链接
+    let dir = state.backup_path.join(version.to_string());
+    let linked = async {
+        for (rel, src) in &files {
+            let dst = rel.split('/').fold(dir.clone(), |path, seg| path.join(seg));
+            if link_file(src, &dst).await.is_err() {
+                store_file(src, &dst).await?;
+            }
+        }
+        anyhow::Result::<_>::Ok(())
+    }
+    .await;
+    if let Err(e) = linked {
+        let dir = dir.clone();
+        tokio::task::spawn_blocking(move || remove_dir_all::remove_dir_all(dir)).await??;
+        return Err(e);
+    }
+
+    let manifest = Arc::new(ManifestData {
+        blob,
+        data: Arc::new(data),
+    });
+    let snapshot = Arc::new(Snapshot::new(version, dir, manifest, vec![]));
+    snapshot.save_manifest().await?;
+    state
+        .blobs
+        .publish(&state.snapshots, snapshot.clone())
+        .await?;
+    state.metrics.retain(&snapshot);
+    log::info!(target: "replication", "Switched to replicated version {version}");
+    Ok(())
+}
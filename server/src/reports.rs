@@ -0,0 +1,74 @@
+use std::cmp::Reverse;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use dashmap::DashMap;
+use model::SyncReport;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+const REPORT_EXT: &str = "report";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredReport {
+    pub received_at: SystemTime,
+    pub ip: IpAddr,
+    pub report: SyncReport,
+}
+
+/// 每个客户端最近一次的同步结果，保存在磁盘上以便重启后仍可查询
+#[derive(Debug)]
+pub struct ReportStore {
+    dir: PathBuf,
+    latest: DashMap<Uuid, StoredReport>,
+}
+
+impl ReportStore {
+    pub async fn load(dir: PathBuf) -> anyhow::Result<Self> {
+        tokio::fs::create_dir_all(&dir).await?;
+        let latest = DashMap::new();
+        let mut read_dir = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            let path = entry.path();
+            if path.extension().map(|a| a != REPORT_EXT).unwrap_or(true) {
+                continue;
+            }
+            let stored = tokio::fs::read(&path)
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|blob| Ok(rmp_serde::from_slice::<StoredReport>(&blob)?));
+            match stored {
+                Ok(stored) => {
+                    latest.insert(stored.report.client_id, stored);
+                }
+                Err(e) => log::warn!(target: "report", "Skip invalid report {path:?}: {e}"),
+            }
+        }
+        Ok(Self { dir, latest })
+    }
+
+    pub async fn insert(&self, ip: IpAddr, report: SyncReport) -> anyhow::Result<()> {
+        let stored = StoredReport {
+            received_at: SystemTime::now(),
+            ip,
+            report,
+        };
+        let mut path = self.dir.join(stored.report.client_id.to_string());
+        path.set_extension(REPORT_EXT);
+        tokio::fs::write(path, rmp_serde::to_vec(&stored)?).await?;
+        self.latest.insert(stored.report.client_id, stored);
+        Ok(())
+    }
+
+    pub fn get(&self, client_id: &Uuid) -> Option<StoredReport> {
+        self.latest.get(client_id).map(|a| a.clone())
+    }
+
+    /// 按接收时间倒序
+    pub fn list(&self) -> Vec<StoredReport> {
+        let mut list: Vec<_> = self.latest.iter().map(|a| a.value().clone()).collect();
+        list.sort_by_key(|a| Reverse(a.received_at));
+        list
+    }
+}
@@ -1,9 +1,21 @@
 use dashmap::DashMap;
 use headers::Range;
-use model::Manifest;
+use ipnet::IpNet;
+use model::{HookConfig, Manifest, UPDATE_HEADER, VERSION_HEADER};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 use uuid::Uuid;
+
+use crate::access::{AccessConfig, AccessControl};
+use crate::blobs::BlobStore;
+use crate::clients::ClientRegistry;
+use crate::init_log::{LogConfig, LogHandle};
+use crate::metrics::Metrics;
+use crate::relay::{Relay, RelayConfig};
+use crate::replication::{ReplicationConfig, Replicator};
+use crate::reports::ReportStore;
+use crate::snapshot::{Snapshot, Snapshots};
+use crate::updates::ClientUpdates;
 use warp::{
     http::*,
     hyper::{body::Bytes, header::*, Body},
@@ -11,10 +23,44 @@ use warp::{
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub content_path: PathBuf,
     pub server_addr: SocketAddr,
     pub remove_ext: String,
+    /// 是否把后缀为 `remove_ext` 的文件当作删除标记，关闭后这类文件会正常同步，
+    /// 删除改用内容根目录下的 `syner-removed.toml`
+    pub remove_ext_marker: bool,
+    /// 发布时将内容固定到快照目录的方式。发布后快照中的文件改为文件库的硬链接，
+    /// 复制出的文件只在发布过程中暂时占用空间
+    pub freeze_mode: FreezeMode,
+    /// 保留的历史快照数量，用于回滚。回滚只能在控制台中用 `rollback` 指令进行，
+    /// 控制台被禁用时无法回滚
+    pub snapshot_retention: usize,
+    /// `/metrics` 中导出下载次数的文件数量
+    pub metrics_top_files: usize,
+    /// 受信任的反向代理（CIDR），只有来自这些地址的转发头才会被采信
+    pub trusted_proxies: Vec<IpNet>,
+    /// 退出时等待进行中的请求完成的最长时间
+    #[serde(with = "crate::utils::humantime_duration")]
+    pub shutdown_timeout: Duration,
+    /// `/peers` 只列出这段时间内访问过的客户端
+    #[serde(with = "crate::utils::humantime_duration")]
+    pub peer_ttl: Duration,
+    /// 全局忽略规则，语法与 .gitignore 相同，内容文件夹中还可以放置 `.synerignore`
+    pub ignore: Vec<String>,
+    /// 黑白名单与按 IP 的限流
+    pub access: AccessConfig,
+    /// 日志级别、文件夹与切分
+    pub log: LogConfig,
+    /// 中继模式，从上游服务器获取清单和文件
+    pub relay: RelayConfig,
+    /// 主从复制
+    pub replication: ReplicationConfig,
+    /// 发布前后执行的命令，`before` 在新版本对客户端生效前执行
+    pub hooks: HookConfig,
+    /// 客户端自动更新的程序，文件名为平台名，如 `windows-x86_64.exe`
+    pub update_path: PathBuf,
 }
 
 impl Default for Config {
@@ -23,29 +69,94 @@ impl Default for Config {
             content_path: "./content".into(),
             server_addr: ([0, 0, 0, 0], 16342).into(),
             remove_ext: "del".into(),
+            remove_ext_marker: true,
+            freeze_mode: Default::default(),
+            snapshot_retention: 5,
+            metrics_top_files: 20,
+            trusted_proxies: vec![],
+            shutdown_timeout: Duration::from_secs(30),
+            peer_ttl: Duration::from_secs(60 * 60),
+            ignore: [".DS_Store", "Thumbs.db", "desktop.ini", "*.swp", "*~"]
+                .map(String::from)
+                .to_vec(),
+            access: Default::default(),
+            log: Default::default(),
+            relay: Default::default(),
+            replication: Default::default(),
+            hooks: Default::default(),
+            update_path: "./updates".into(),
         }
     }
 }
 
+/// 各线程共享的服务器状态
+pub struct ServerState {
+    pub config: Arc<Config>,
+    pub content_path: Arc<PathBuf>,
+    pub backup_path: Arc<PathBuf>,
+    pub snapshots: Snapshots,
+    pub blobs: Arc<BlobStore>,
+    pub metrics: Arc<Metrics>,
+    pub reports: Arc<ReportStore>,
+    pub clients: Arc<ClientRegistry>,
+    pub access: Arc<AccessControl>,
+    pub log: Arc<LogHandle>,
+    /// 中继模式下的上游与缓存
+    pub relay: Option<Arc<Relay>>,
+    /// 主服务器向从服务器推送新版本
+    pub replicator: Option<Arc<Replicator>>,
+    /// 客户端自动更新，中继模式下不提供
+    pub updates: Option<Arc<ClientUpdates>>,
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum FreezeMode {
+    /// 硬链接，开销最小，但内容文件被原地修改时快照也会改变
+    HardLink,
+    /// 写时复制，文件系统不支持时回退到复制
+    #[default]
+    Reflink,
+    /// 完整复制
+    Copy,
+}
+
+/// 发布过程中被修改的文件
+pub type ChangedFiles = Arc<std::sync::Mutex<Vec<PathBuf>>>;
+
+/// 发布时被忽略规则排除的文件（相对内容文件夹）
+pub type ExcludedFiles = Arc<std::sync::Mutex<Vec<String>>>;
+
 #[derive(Debug)]
 pub struct ManifestData {
     pub blob: Vec<u8>,
     pub data: Manifest,
 }
 
-pub struct ManifestReply(pub Arc<ManifestData>);
+pub struct ManifestReply {
+    pub snapshot: Arc<Snapshot>,
+    /// 旧客户端使用展开 glob 后的清单
+    pub legacy: bool,
+    /// 随清单发布的客户端程序（`UPDATE_HEADER` 的值）
+    pub update: Option<String>,
+}
 
 impl Reply for ManifestReply {
     fn into_response(self) -> warp::reply::Response {
-        let response = warp::http::Response::builder()
+        let mut response = warp::http::Response::builder()
             .header(CONTENT_TYPE, "application/msgpack")
-            .header(CONTENT_LENGTH, self.0.blob.len());
+            .header(CONTENT_LENGTH, self.as_ref().len())
+            .header(VERSION_HEADER, self.snapshot.version.to_string());
+        if let Some(update) = &self.update {
+            response = response.header(UPDATE_HEADER, update);
+        }
         response.body(Body::from(Bytes::from_owner(self))).unwrap()
     }
 }
 
 impl AsRef<[u8]> for ManifestReply {
     fn as_ref(&self) -> &[u8] {
-        self.0.blob.as_ref()
+        self.snapshot.blob(self.legacy)
     }
 }
@@ -0,0 +1,250 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use dashmap::DashMap;
+use model::ItemOp;
+use ulid::Ulid;
+
+use crate::server_model::ManifestData;
+use crate::tombstones::expand_globs;
+
+const MANIFEST_EXT: &str = "manifest";
+
+/// 一次发布的结果：清单与其对应的只读内容目录
+///
+/// 清单和内容目录总是作为一个整体被替换，
+/// 被回收的快照在最后一个引用（进行中的请求）释放时删除
+#[derive(Debug)]
+pub struct Snapshot {
+    pub version: Ulid,
+    pub dir: PathBuf,
+    pub manifest: Arc<ManifestData>,
+    /// 发布时被忽略的文件，从磁盘读取的历史快照中为空
+    pub excluded: Vec<String>,
+    /// 提供给旧客户端的清单，没有 glob 时为 `None`
+    legacy: OnceLock<Option<Vec<u8>>>,
+    /// 清单中要同步的文件的哈希，第一次按哈希查询时生成
+    hashes: OnceLock<HashSet<String>>,
+    retired: AtomicBool,
+}
+
+impl Snapshot {
+    pub fn new(
+        version: Ulid,
+        dir: PathBuf,
+        manifest: Arc<ManifestData>,
+        excluded: Vec<String>,
+    ) -> Self {
+        Self {
+            version,
+            dir,
+            manifest,
+            excluded,
+            legacy: OnceLock::new(),
+            hashes: OnceLock::new(),
+            retired: AtomicBool::new(false),
+        }
+    }
+
+    /// 从服务器收到第一次推送前提供的空快照，不保存到磁盘，也不计入历史
+    pub fn placeholder() -> anyhow::Result<Self> {
+        let data: DashMap<String, model::ManifestItem> = DashMap::new();
+        let manifest = Arc::new(ManifestData {
+            blob: rmp_serde::to_vec(&data)?,
+            data: Arc::new(data),
+        });
+        Ok(Self::new(Ulid::nil(), PathBuf::new(), manifest, vec![]))
+    }
+
+    pub fn is_placeholder(&self) -> bool {
+        self.version.is_nil()
+    }
+
+    /// 旧客户端不支持 `RemoveGlob`，按保留的快照展开清单中的 glob
+    pub fn init_legacy(&self, history: &[Arc<Snapshot>]) -> anyhow::Result<()> {
+        if self.legacy.get().is_none() {
+            let _ = self.legacy.set(expand_globs(&self.manifest.data, history)?);
+        }
+        Ok(())
+    }
+
+    /// 提供给客户端的清单，`legacy` 时使用 [`Snapshot::init_legacy`] 展开后的清单
+    pub fn blob(&self, legacy: bool) -> &[u8] {
+        match self.legacy.get() {
+            Some(Some(blob)) if legacy => blob,
+            _ => &self.manifest.blob,
+        }
+    }
+
+    /// 清单中是否有内容为 `hash`（小写十六进制）的要同步的文件
+    pub fn references(&self, hash: &str) -> bool {
+        self.hashes
+            .get_or_init(|| {
+                self.manifest
+                    .data
+                    .iter()
+                    .filter(|a| a.value().0 == ItemOp::Sync)
+                    .map(|a| base16ct::lower::encode_string(&a.value().2))
+                    .collect()
+            })
+            .contains(hash)
+    }
+
+    /// 标记为已回收，引用全部释放后删除内容目录和清单
+    pub fn retire(&self) {
+        self.retired.store(true, Ordering::Release);
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.dir.with_extension(MANIFEST_EXT)
+    }
+
+    /// 将清单保存在快照目录旁，重启后仍可回滚
+    pub async fn save_manifest(&self) -> anyhow::Result<()> {
+        tokio::fs::write(self.manifest_path(), &self.manifest.blob).await?;
+        Ok(())
+    }
+
+    async fn load(dir: PathBuf, version: Ulid) -> anyhow::Result<Self> {
+        let blob = tokio::fs::read(dir.with_extension(MANIFEST_EXT)).await?;
+        let data: DashMap<_, _> = rmp_serde::from_slice(&blob)?;
+        let manifest = Arc::new(ManifestData {
+            blob,
+            data: Arc::new(data),
+        });
+        Ok(Self::new(version, dir, manifest, vec![]))
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if !*self.retired.get_mut() {
+            return;
+        }
+        let version = self.version;
+        let manifest_path = self.manifest_path();
+        let dir = std::mem::take(&mut self.dir);
+        let remove = move || {
+            log::info!(target: "snapshot", "Removing snapshot {version} {dir:?}");
+            if let Err(e) = std::fs::remove_file(&manifest_path) {
+                log::error!(target: "snapshot", "Remove manifest of {version} failed: {e}");
+            }
+            if let Err(e) = remove_dir_all::remove_dir_all(&dir) {
+                log::error!(target: "snapshot", "Remove snapshot {version} failed: {e}");
+            }
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => drop(handle.spawn_blocking(remove)),
+            Err(_) => remove(),
+        }
+    }
+}
+
+/// 已发布的快照历史及当前对外提供的快照
+#[derive(Debug)]
+pub struct SnapshotStore {
+    retention: usize,
+    current: tokio::sync::RwLock<Arc<Snapshot>>,
+    history: std::sync::Mutex<BTreeMap<Ulid, Arc<Snapshot>>>,
+}
+
+pub type Snapshots = Arc<SnapshotStore>;
+
+impl SnapshotStore {
+    /// 读取上次运行保留下来的快照，并删除没有清单的不完整快照
+    pub async fn load_history(backup_path: &Path) -> anyhow::Result<Vec<Arc<Snapshot>>> {
+        let mut history = vec![];
+        let mut read_dir = tokio::fs::read_dir(backup_path).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            let path = entry.path();
+            if !entry.file_type().await?.is_dir() {
+                continue;
+            }
+            let version = path
+                .file_name()
+                .and_then(|name| Ulid::from_string(&name.to_string_lossy()).ok());
+            let snapshot = match version {
+                Some(version) => Snapshot::load(path.clone(), version).await,
+                None => Err(anyhow::anyhow!("not a snapshot")),
+            };
+            match snapshot {
+                Ok(snapshot) => {
+                    log::info!(target: "snapshot", "Found snapshot {}", snapshot.version);
+                    history.push(Arc::new(snapshot));
+                }
+                Err(e) => {
+                    log::warn!(target: "snapshot", "Removing incomplete snapshot {path:?}: {e}");
+                    tokio::task::spawn_blocking(move || remove_dir_all::remove_dir_all(path))
+                        .await??;
+                }
+            }
+        }
+        Ok(history)
+    }
+
+    pub fn new(retention: usize, current: Arc<Snapshot>, history: Vec<Arc<Snapshot>>) -> Self {
+        let store = Self {
+            retention: retention.max(1),
+            current: tokio::sync::RwLock::new(current.clone()),
+            history: std::sync::Mutex::new(
+                history
+                    .into_iter()
+                    .chain(Some(current).filter(|a| !a.is_placeholder()))
+                    .map(|snapshot| (snapshot.version, snapshot))
+                    .collect(),
+            ),
+        };
+        store.collect_garbage(store.current.try_read().unwrap().version);
+        store
+    }
+
+    pub async fn current(&self) -> Arc<Snapshot> {
+        self.current.read().await.clone()
+    }
+
+    /// 所有保留的快照，按发布时间排序
+    pub fn list(&self) -> Vec<Arc<Snapshot>> {
+        self.history.lock().unwrap().values().cloned().collect()
+    }
+
+    /// 原子地切换到新的快照，并回收超出保留数量的旧快照
+    pub async fn publish(&self, new: Arc<Snapshot>) {
+        let version = new.version;
+        self.history
+            .lock()
+            .unwrap()
+            .insert(version, new.clone());
+        let old = std::mem::replace(&mut *self.current.write().await, new);
+        log::info!(target: "snapshot", "Published snapshot {version}, previous {}", old.version);
+        self.collect_garbage(version);
+    }
+
+    /// 重新发布一个保留的快照，不需要重新计算哈希
+    pub async fn rollback(&self, version: Ulid) -> anyhow::Result<()> {
+        let Some(snapshot) = self.history.lock().unwrap().get(&version).cloned() else {
+            anyhow::bail!("快照 {version} 不存在或已被回收");
+        };
+        let old = std::mem::replace(&mut *self.current.write().await, snapshot);
+        log::info!(target: "snapshot", "Rolled back to snapshot {version}, previous {}", old.version);
+        Ok(())
+    }
+
+    /// 保留最近的若干个快照以及当前快照
+    fn collect_garbage(&self, current: Ulid) {
+        let mut history = self.history.lock().unwrap();
+        let expired: Vec<_> = history
+            .keys()
+            .rev()
+            .skip(self.retention)
+            .filter(|version| **version != current)
+            .copied()
+            .collect();
+        for version in expired {
+            if let Some(snapshot) = history.remove(&version) {
+                snapshot.retire();
+            }
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use model::{ItemOp, Manifest, ManifestItem};
+use serde::Deserialize;
+
+use crate::snapshot::Snapshot;
+
+/// 内容根目录下的删除列表，列出需要从客户端删除的文件
+///
+/// ```toml
+/// remove = ["old.txt", "old_assets/**"]
+/// ```
+pub const TOMBSTONE_FILE: &str = "syner-removed.toml";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Tombstones {
+    /// 精确路径或 glob，`*` 不跨目录，`**` 匹配任意层目录
+    pub remove: Vec<String>,
+}
+
+impl Tombstones {
+    pub async fn load(content_path: &Path) -> anyhow::Result<Self> {
+        let path = content_path.join(TOMBSTONE_FILE);
+        if !tokio::fs::try_exists(&path).await? {
+            return Ok(Self::default());
+        }
+        let str = tokio::fs::read_to_string(&path).await?;
+        let tombstones: Self =
+            toml::from_str(&str).map_err(|e| anyhow::anyhow!("{TOMBSTONE_FILE} 格式错误: {e}"))?;
+        for pattern in &tombstones.remove {
+            let pattern = normalize(pattern)?;
+            if is_glob(&pattern) {
+                globset::Glob::new(&pattern)
+                    .map_err(|e| anyhow::anyhow!("{TOMBSTONE_FILE} 中的 {pattern:?} 无效: {e}"))?;
+            }
+        }
+        Ok(tombstones)
+    }
+
+    /// 写入清单，与要同步的文件同名的删除项被忽略
+    pub fn apply(&self, manifest: &Manifest) -> anyhow::Result<()> {
+        for pattern in &self.remove {
+            let pattern = normalize(pattern)?;
+            let op = if is_glob(&pattern) {
+                ItemOp::RemoveGlob
+            } else {
+                ItemOp::Remove
+            };
+            if let Some(item) = manifest.get(&pattern) {
+                if item.0 == ItemOp::Sync {
+                    log::warn!(target: "manifest", "{pattern:?} is listed in {TOMBSTONE_FILE} but also exists in content, keeping the file");
+                    continue;
+                }
+            }
+            log::info!(target: "manifest", "Tombstone {pattern:?} {{ op = {op:?} }}");
+            manifest.insert(pattern, (op, 0, Default::default()));
+        }
+        Ok(())
+    }
+}
+
+/// 旧客户端不支持 `RemoveGlob`，把 glob 展开为保留的快照中同步过、当前不再同步的匹配文件，
+/// 返回展开后的清单，清单中没有 glob 时返回 `None`
+pub fn expand_globs(
+    manifest: &Manifest,
+    history: &[Arc<Snapshot>],
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut matchers = vec![];
+    let mut legacy = BTreeMap::<String, ManifestItem>::new();
+    for item in manifest.iter() {
+        if item.value().0 == ItemOp::RemoveGlob {
+            // 与客户端展开 glob 的规则相同
+            let glob = globset::GlobBuilder::new(item.key())
+                .literal_separator(true)
+                .build()?;
+            matchers.push(glob.compile_matcher());
+        } else {
+            legacy.insert(item.key().clone(), item.value().clone());
+        }
+    }
+    if matchers.is_empty() {
+        return Ok(None);
+    }
+    for snapshot in history {
+        for item in snapshot.manifest.data.iter() {
+            let path = item.key();
+            if item.value().0 == ItemOp::Sync
+                && !legacy.contains_key(path)
+                && matchers.iter().any(|a| a.is_match(path))
+            {
+                legacy.insert(path.clone(), (ItemOp::Remove, 0, Default::default()));
+            }
+        }
+    }
+    Ok(Some(rmp_serde::to_vec(&legacy)?))
+}
+
+fn normalize(pattern: &str) -> anyhow::Result<String> {
+    let pattern = pattern.trim().replace('\\', "/");
+    let pattern = pattern.trim_start_matches('/');
+    if pattern.is_empty() || pattern.split('/').any(|seg| seg == "..") {
+        anyhow::bail!("{TOMBSTONE_FILE} 中的 {pattern:?} 无效");
+    }
+    Ok(pattern.to_string())
+}
+
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '[', '{'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use dashmap::DashMap;
+    use serde_bytes::ByteBuf;
+    use ulid::Ulid;
+
+    use crate::server_model::ManifestData;
+
+    fn manifest(items: &[(&str, ItemOp)]) -> Manifest {
+        let map: DashMap<_, _> = items
+            .iter()
+            .map(|(path, op)| (path.to_string(), (*op, 0, ByteBuf::new())))
+            .collect();
+        Arc::new(map)
+    }
+
+    fn snapshot(manifest: Manifest) -> Arc<Snapshot> {
+        let data = Arc::new(ManifestData {
+            blob: rmp_serde::to_vec(&*manifest).unwrap(),
+            data: manifest,
+        });
+        Arc::new(Snapshot::new(Ulid::new(), PathBuf::new(), data, vec![]))
+    }
+
+    fn tombstones(remove: &[&str]) -> Tombstones {
+        Tombstones {
+            remove: remove.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn exact_paths_and_globs() {
+        let map = manifest(&[("keep.txt", ItemOp::Sync)]);
+        tombstones(&[
+            "old.txt",
+            "\\logs\\*.log",
+            "/assets/**",
+            "a?.txt",
+            "{x,y}.dat",
+        ])
+        .apply(&map)
+        .unwrap();
+        assert_eq!(map.get("old.txt").unwrap().0, ItemOp::Remove);
+        assert_eq!(map.get("logs/*.log").unwrap().0, ItemOp::RemoveGlob);
+        assert_eq!(map.get("assets/**").unwrap().0, ItemOp::RemoveGlob);
+        assert_eq!(map.get("a?.txt").unwrap().0, ItemOp::RemoveGlob);
+        assert_eq!(map.get("{x,y}.dat").unwrap().0, ItemOp::RemoveGlob);
+        assert_eq!(map.get("keep.txt").unwrap().0, ItemOp::Sync);
+    }
+
+    #[test]
+    fn synced_file_wins_over_tombstone() {
+        let map = manifest(&[("keep.txt", ItemOp::Sync)]);
+        tombstones(&["keep.txt"]).apply(&map).unwrap();
+        assert_eq!(map.get("keep.txt").unwrap().0, ItemOp::Sync);
+    }
+
+    #[test]
+    fn rejects_escaping_paths() {
+        let map = manifest(&[]);
+        assert!(tombstones(&["../outside.txt"]).apply(&map).is_err());
+        assert!(tombstones(&["a/../../b"]).apply(&map).is_err());
+        assert!(tombstones(&["  "]).apply(&map).is_err());
+        assert!(tombstones(&["/"]).apply(&map).is_err());
+        // `..` 只是文件名的一部分时允许
+        assert!(tombstones(&["a..b.txt"]).apply(&map).is_ok());
+    }
+
+    #[test]
+    fn expand_globs_for_legacy_clients() {
+        let current = manifest(&[
+            ("mods/new.jar", ItemOp::Sync),
+            ("mods/*.jar", ItemOp::RemoveGlob),
+            ("old.txt", ItemOp::Remove),
+        ]);
+        let history = vec![
+            snapshot(manifest(&[
+                ("mods/old.jar", ItemOp::Sync),
+                ("mods/new.jar", ItemOp::Sync),
+                ("mods/sub/deep.jar", ItemOp::Sync),
+                ("mods/readme.txt", ItemOp::Sync),
+            ])),
+            snapshot(manifest(&[("mods/older.jar", ItemOp::Sync)])),
+        ];
+        let blob = expand_globs(&current, &history).unwrap().unwrap();
+        let legacy: BTreeMap<String, ManifestItem> = rmp_serde::from_slice(&blob).unwrap();
+        let ops: Vec<_> = legacy.iter().map(|(k, v)| (k.as_str(), v.0)).collect();
+        // `*` 不跨目录，与客户端展开的规则相同；仍要同步的文件不删除
+        assert_eq!(
+            ops,
+            vec![
+                ("mods/new.jar", ItemOp::Sync),
+                ("mods/old.jar", ItemOp::Remove),
+                ("mods/older.jar", ItemOp::Remove),
+                ("old.txt", ItemOp::Remove),
+            ]
+        );
+    }
+
+    #[test]
+    fn expand_globs_without_globs() {
+        let current = manifest(&[("a.txt", ItemOp::Sync), ("b.txt", ItemOp::Remove)]);
+        assert!(expand_globs(&current, &[]).unwrap().is_none());
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use model::{calc_hash, ClientUpdate};
+use serde_bytes::ByteBuf;
+
+use crate::blobs::BlobStore;
+
+/// 各平台的客户端程序，文件名去掉扩展名即平台名，如 `windows-x86_64.exe`
+pub struct ClientUpdates {
+    dir: PathBuf,
+    entries: RwLock<HashMap<String, ClientUpdate>>,
+}
+
+impl ClientUpdates {
+    pub async fn load(dir: PathBuf, blobs: &BlobStore) -> anyhow::Result<Self> {
+        let updates = Self {
+            dir,
+            entries: Default::default(),
+        };
+        updates.reload(blobs).await?;
+        Ok(updates)
+    }
+
+    /// 重新扫描文件夹并写入文件库，返回平台数量
+    pub async fn reload(&self, blobs: &BlobStore) -> anyhow::Result<usize> {
+        let mut entries = HashMap::new();
+        let mut files = vec![];
+        if tokio::fs::try_exists(&self.dir).await? {
+            let mut dir = tokio::fs::read_dir(&self.dir).await?;
+            while let Some(entry) = dir.next_entry().await? {
+                let path = entry.path();
+                let meta = entry.metadata().await?;
+                let Some(platform) = path.file_stem().and_then(|a| a.to_str()) else {
+                    continue;
+                };
+                if !meta.is_file() || platform.starts_with('.') {
+                    continue;
+                }
+                let hash = calc_hash(tokio::fs::File::open(&path).await?).await?;
+                files.push((path.clone(), base16ct::lower::encode_string(&hash)));
+                entries.insert(
+                    platform.to_string(),
+                    ClientUpdate {
+                        len: meta.len(),
+                        hash: ByteBuf::from(hash),
+                    },
+                );
+            }
+        }
+        blobs.pin(&files).await?;
+        let count = entries.len();
+        for (platform, update) in &entries {
+            log::info!(target: "update", "Client update {platform}: {} bytes", update.len);
+        }
+        *self.entries.write().unwrap() = entries;
+        Ok(count)
+    }
+
+    /// 清单响应中的 `UPDATE_HEADER`，没有任何平台的程序时为 `None`
+    pub fn header(&self) -> Option<String> {
+        let entries = self.entries.read().unwrap();
+        let mut platforms: Vec<_> = entries.keys().collect();
+        platforms.sort();
+        let header: Vec<String> = platforms
+            .into_iter()
+            .map(|a| entries[a].to_header(a))
+            .collect();
+        (!header.is_empty()).then(|| header.join(","))
+    }
+}
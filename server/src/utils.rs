@@ -1,8 +1,13 @@
 use std::fmt;
+use std::fs::Metadata;
 use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
 use headers::Header;
 use headers::HeaderValue;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use warp::hyper::client::HttpConnector;
+use warp::hyper::Client;
 use warp::Filter;
 use warp::Rejection;
 
@@ -17,6 +22,27 @@ pub fn is_valid_path(path: &str) -> bool {
     !path.contains('\0') && !path.split('/').any(|part| part.is_empty())
 }
 
+/// 访问其他服务器（上游、从服务器）的客户端
+pub type HttpClient = Client<HttpsConnector<HttpConnector>>;
+
+/// 同时支持 http 与 https，https 使用内置的根证书
+pub fn http_client() -> HttpClient {
+    let mut connector = HttpConnector::new();
+    connector.set_connect_timeout(Some(Duration::from_secs(10)));
+    connector.enforce_http(false);
+    let connector = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .wrap_connector(connector);
+    Client::builder().build(connector)
+}
+
+/// 用于检测文件在处理过程中是否被修改
+pub fn file_stamp(meta: &Metadata) -> (u64, Option<SystemTime>) {
+    (meta.len(), meta.modified().ok())
+}
+
 pub fn optional_header<T: Header + Send + 'static>(
 ) -> impl Filter<Extract = (Option<T>,), Error = Rejection> + Clone {
     warp::header::optional(T::name().as_str()).and_then(
@@ -58,3 +84,19 @@ impl fmt::Display for InvalidHeader {
         write!(f, "Invalid request header {:?}", self.name)
     }
 }
+
+/// 以 humantime 格式（如 `30s`、`1h 30m`）序列化 `Duration`
+pub mod humantime_duration {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&humantime::format_duration(*value).to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let str = String::deserialize(deserializer)?;
+        humantime::parse_duration(&str).map_err(serde::de::Error::custom)
+    }
+}
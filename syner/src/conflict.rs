@@ -0,0 +1,121 @@
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use globset::{GlobBuilder, GlobMatcher};
+use slint::{SharedString, Weak};
+use tokio::sync::oneshot;
+
+use crate::client_model::{ConflictConfig, ConflictPolicy};
+use crate::{AppWindow, ModelConflictChoice};
+
+/// 冲突文件的备份文件夹，位于 `cwd` 下，删除用的 glob 不会匹配其中的文件
+pub const BACKUP_DIR: &str = ".syner-backup";
+
+/// 一次同步中的冲突策略与已发生的冲突
+pub struct Conflicts {
+    rules: Vec<(GlobMatcher, ConflictPolicy)>,
+    default: ConflictPolicy,
+    /// 同一时间只在界面上询问一个文件
+    asking: tokio::sync::Mutex<()>,
+    answer: Mutex<Option<oneshot::Sender<ConflictPolicy>>>,
+    found: Mutex<Vec<(String, ConflictPolicy)>>,
+}
+
+impl Conflicts {
+    pub fn new(config: &ConflictConfig) -> anyhow::Result<Self> {
+        let mut rules = vec![];
+        for rule in &config.rules {
+            let matcher = GlobBuilder::new(&rule.pattern)
+                .literal_separator(true)
+                .build()?
+                .compile_matcher();
+            rules.push((matcher, rule.policy));
+        }
+        Ok(Self {
+            rules,
+            default: config.default,
+            asking: Default::default(),
+            answer: Default::default(),
+            found: Default::default(),
+        })
+    }
+
+    pub fn policy(&self, rel: &str) -> ConflictPolicy {
+        self.rules
+            .iter()
+            .find(|a| a.0.is_match(rel))
+            .map_or(self.default, |a| a.1)
+    }
+
+    /// 按策略决定处理方式，需要询问时等待界面选择，并记录到本次同步的冲突中
+    pub async fn resolve(&self, ui: Weak<AppWindow>, rel: &str) -> ConflictPolicy {
+        let mut policy = self.policy(rel);
+        if policy == ConflictPolicy::Ask {
+            let _asking = self.asking.lock().await;
+            let (tx, rx) = oneshot::channel();
+            *self.answer.lock().unwrap() = Some(tx);
+            let path = SharedString::from(rel);
+            let shown = tokio::task::spawn_blocking(move || {
+                ui.upgrade_in_event_loop(move |ui| ui.invoke_ask_conflict(path))
+            })
+            .await;
+            // 界面已关闭时保留本地文件
+            policy = match shown {
+                Ok(Ok(())) => rx.await.unwrap_or(ConflictPolicy::Skip),
+                _ => ConflictPolicy::Skip,
+            };
+        }
+        println!("Conflict {rel} {policy:?}");
+        self.found.lock().unwrap().push((rel.to_string(), policy));
+        policy
+    }
+
+    /// 界面上的选择
+    pub fn answer(&self, choice: ModelConflictChoice) {
+        let policy = match choice {
+            ModelConflictChoice::Overwrite => ConflictPolicy::Overwrite,
+            ModelConflictChoice::Skip => ConflictPolicy::Skip,
+            ModelConflictChoice::Backup => ConflictPolicy::Backup,
+        };
+        if let Some(tx) = self.answer.lock().unwrap().take() {
+            let _ = tx.send(policy);
+        }
+    }
+
+    /// 取出本次同步的冲突，按路径排序
+    pub fn take(&self) -> Vec<(String, ConflictPolicy)> {
+        let mut found = std::mem::take(&mut *self.found.lock().unwrap());
+        found.sort_by(|a, b| a.0.cmp(&b.0));
+        found
+    }
+}
+
+/// 同步结果中显示的处理方式
+pub fn conflict_label(policy: ConflictPolicy) -> &'static str {
+    match policy {
+        ConflictPolicy::Overwrite | ConflictPolicy::Ask => "已覆盖",
+        ConflictPolicy::Skip => "已保留本地文件",
+        ConflictPolicy::Backup => "已备份后覆盖",
+    }
+}
+
+/// 把本地文件复制到备份文件夹，已有同名备份时追加序号
+pub async fn backup(cwd: &Path, rel: &str) -> anyhow::Result<PathBuf> {
+    let base = rel
+        .split('/')
+        .fold(cwd.join(BACKUP_DIR), |path, seg| path.join(seg));
+    if let Some(dir) = base.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let mut target = base.clone();
+    let mut n = 0;
+    while tokio::fs::try_exists(&target).await? {
+        n += 1;
+        let mut name = base.as_os_str().to_owned();
+        name.push(format!(".{n}"));
+        target = PathBuf::from(name);
+    }
+    tokio::fs::copy(cwd.join(rel), &target).await?;
+    println!("Backup {rel} => {target:?}");
+    Ok(target)
+}
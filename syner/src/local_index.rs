@@ -0,0 +1,398 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use model::ItemOp;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::client_model::ClientManifest;
+use crate::protected::Protected;
+
+/// 保存在 syner.toml 旁的本地文件索引
+pub const INDEX_PATH: &str = "syner-index";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+    pub hash: ByteBuf,
+}
+
+/// 已校验过哈希的本地文件，文件大小和修改时间不变时记录的哈希可信
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct LocalIndex {
+    cwd: PathBuf,
+    files: HashMap<String, IndexEntry>,
+}
+
+impl LocalIndex {
+    /// 读取失败或同步目录已改变时返回空索引
+    pub fn load(path: &Path, cwd: &Path) -> Self {
+        let index: Option<Self> = std::fs::read(path)
+            .ok()
+            .and_then(|blob| rmp_serde::from_slice(&blob).ok());
+        match index {
+            Some(index) if index.cwd == cwd => index,
+            _ => Self {
+                cwd: cwd.to_path_buf(),
+                files: HashMap::new(),
+            },
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, rmp_serde::to_vec(self)?)?;
+        Ok(())
+    }
+
+    fn stamp(&self, rel: &str) -> Option<(u64, Option<SystemTime>)> {
+        let meta = std::fs::metadata(self.cwd.join(rel)).ok()?;
+        meta.is_file().then(|| (meta.len(), meta.modified().ok()))
+    }
+
+    pub fn verified_hash(&self, rel: &str) -> Option<&ByteBuf> {
+        let entry = self.files.get(rel)?;
+        let (len, modified) = self.stamp(rel)?;
+        (modified.is_some() && entry.len == len && entry.modified == modified)
+            .then_some(&entry.hash)
+    }
+
+    /// 上次同步后在本地被修改过的文件，返回上次同步时的哈希
+    pub fn modified_hash(&self, rel: &str) -> Option<&ByteBuf> {
+        let entry = self.files.get(rel)?;
+        let (len, modified) = self.stamp(rel)?;
+        (modified.is_none() || entry.len != len || entry.modified != modified)
+            .then_some(&entry.hash)
+    }
+
+    /// 同步写入或校验过的文件，不在索引中的本地文件不是同步器创建的
+    pub fn contains(&self, rel: &str) -> bool {
+        self.files.contains_key(rel)
+    }
+
+    /// 记录刚校验过的文件
+    pub fn insert(&mut self, rel: &str, hash: ByteBuf) {
+        if let Some((len, modified)) = self.stamp(rel) {
+            self.files.insert(
+                rel.to_string(),
+                IndexEntry {
+                    len,
+                    modified,
+                    hash,
+                },
+            );
+        }
+    }
+
+    pub fn remove(&mut self, rel: &str) {
+        self.files.remove(rel);
+    }
+
+    /// 文件被移动后修改时间不变，记录随之移动
+    fn rename(&mut self, from: &str, to: &str) {
+        if let Some(entry) = self.files.remove(from) {
+            self.files.insert(to.to_string(), entry);
+        }
+    }
+}
+
+/// 一次同步中各任务共享的本地文件状态
+#[derive(Debug, Default)]
+pub struct LocalFiles {
+    pub index: Mutex<LocalIndex>,
+    /// 从本地其他路径移动或复制而来、不需要下载的文件
+    pub relocated: HashSet<String>,
+    /// 按哈希记录本次同步已下载的文件
+    downloads: Mutex<HashMap<ByteBuf, Arc<tokio::sync::Mutex<Option<String>>>>>,
+}
+
+impl LocalFiles {
+    pub fn new(index: LocalIndex) -> Self {
+        Self {
+            index: Mutex::new(index),
+            relocated: HashSet::new(),
+            downloads: Default::default(),
+        }
+    }
+
+    /// 索引中已校验且之后未被修改的文件的完整路径和哈希
+    pub fn verified_file(&self, rel: &str) -> Option<(PathBuf, ByteBuf)> {
+        let index = self.index.lock().unwrap();
+        let hash = index.verified_hash(rel)?.clone();
+        Some((index.cwd.join(rel), hash))
+    }
+
+    pub fn modified_hash(&self, rel: &str) -> Option<ByteBuf> {
+        self.index.lock().unwrap().modified_hash(rel).cloned()
+    }
+
+    pub fn is_tracked(&self, rel: &str) -> bool {
+        self.index.lock().unwrap().contains(rel)
+    }
+
+    pub fn is_verified(&self, rel: &str, hash: &ByteBuf) -> bool {
+        self.index
+            .lock()
+            .unwrap()
+            .verified_hash(rel)
+            .is_some_and(|a| a == hash)
+    }
+
+    pub fn verified(&self, rel: &str, hash: ByteBuf) {
+        self.index.lock().unwrap().insert(rel, hash);
+    }
+
+    /// 比较本地已有的文件与清单，`current` 为已计算的当前哈希
+    ///
+    /// 内容与清单相同时记录到索引中并返回 `None`；内容不同时返回是否要按冲突策略处理：
+    /// 不在索引中的文件不是同步器写入的，索引中的文件在上次同步后被修改过才算冲突
+    pub async fn check_existing(
+        &self,
+        rel: &str,
+        path: &Path,
+        current: Option<Vec<u8>>,
+        expected: &ByteBuf,
+    ) -> anyhow::Result<Option<bool>> {
+        let tracked = self.is_tracked(rel);
+        let synced = self.modified_hash(rel);
+        let current = match current {
+            Some(hash) => Some(hash),
+            // 大小不同时内容一定与清单不同，只有判断是否被修改过才需要计算
+            None if synced.is_some() => {
+                Some(model::calc_hash(tokio::fs::File::open(path).await?).await?)
+            }
+            None => None,
+        };
+        if current.as_deref() == Some(&expected[..]) {
+            self.verified(rel, expected.clone());
+            return Ok(None);
+        }
+        Ok(Some(match synced {
+            Some(synced) => current.is_some_and(|a| *synced != a),
+            None => !tracked,
+        }))
+    }
+
+    pub fn removed(&self, rel: &str) {
+        self.index.lock().unwrap().remove(rel);
+    }
+
+    /// 同一内容只下载一次，后到的任务等待先到的任务完成后复制其下载的文件
+    pub async fn claim(&self, hash: &ByteBuf) -> tokio::sync::OwnedMutexGuard<Option<String>> {
+        let lock = self
+            .downloads
+            .lock()
+            .unwrap()
+            .entry(hash.clone())
+            .or_default()
+            .clone();
+        lock.lock_owned().await
+    }
+}
+
+/// 下载前把本地已有的相同内容放到清单中的新路径
+///
+/// 来源是已删除或不再出现在清单中的旧路径时直接移动，否则复制。
+/// 在同步任务开始前依次执行，不会与删除任务冲突
+pub async fn relocate(
+    manifest: &ClientManifest,
+    mut index: LocalIndex,
+    protected: &Protected,
+) -> LocalFiles {
+    let synced: HashSet<&str> = manifest
+        .iter()
+        .filter(|a| a.op == ItemOp::Sync)
+        .map(|a| a.path.2.as_str())
+        .collect();
+
+    let mut sources: HashMap<ByteBuf, Vec<String>> = HashMap::new();
+    for rel in index.files.keys() {
+        if let Some(hash) = index.verified_hash(rel) {
+            sources.entry(hash.clone()).or_default().push(rel.clone());
+        }
+    }
+
+    let mut relocated = HashSet::new();
+    for item in manifest.iter().filter(|a| a.op == ItemOp::Sync) {
+        let rel = &item.path.2;
+        if index.verified_hash(rel).is_some_and(|a| *a == item.hash) {
+            continue;
+        }
+        // 受保护或本地修改过的文件交给同步任务处理
+        if protected.contains(rel) || index.modified_hash(rel).is_some() {
+            continue;
+        }
+        let target = index.cwd.join(&item.path.0);
+        // 大小一致的文件可能已是正确内容，交给同步任务校验
+        if let Ok(meta) = tokio::fs::metadata(&target).await {
+            if meta.len() == item.len {
+                continue;
+            }
+        }
+        let Some(candidates) = sources.get_mut(&item.hash) else {
+            continue;
+        };
+        // 受保护的文件只能复制
+        let movable = candidates
+            .iter()
+            .position(|a| !synced.contains(a.as_str()) && !protected.contains(a));
+        let result = async {
+            if let Some(dir) = target.parent() {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            match movable {
+                Some(pos) => {
+                    let src = candidates.swap_remove(pos);
+                    let from = index.cwd.join(&src);
+                    if tokio::fs::rename(&from, &target).await.is_err() {
+                        // 跨文件系统时回退为复制后删除
+                        tokio::fs::copy(&from, &target).await?;
+                        tokio::fs::remove_file(&from).await?;
+                        index.remove(&src);
+                        index.insert(rel, item.hash.clone());
+                    } else {
+                        index.rename(&src, rel);
+                    }
+                    println!("Move {src} => {rel}");
+                }
+                None => {
+                    let Some(src) = candidates.first() else {
+                        return Ok(false);
+                    };
+                    tokio::fs::copy(index.cwd.join(src), &target).await?;
+                    index.insert(rel, item.hash.clone());
+                    println!("Copy {src} => {rel}");
+                }
+            }
+            anyhow::Result::<_>::Ok(true)
+        }
+        .await;
+        match result {
+            Ok(true) => {
+                candidates.push(rel.clone());
+                relocated.insert(rel.clone());
+            }
+            Ok(false) => {}
+            Err(e) => println!("Relocate {rel} failed {e:?}"),
+        }
+    }
+
+    LocalFiles {
+        relocated,
+        ..LocalFiles::new(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("syner-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    async fn hash_of(path: &Path) -> Vec<u8> {
+        model::calc_hash(tokio::fs::File::open(path).await.unwrap())
+            .await
+            .unwrap()
+    }
+
+    fn local(dir: &TempDir) -> LocalFiles {
+        LocalFiles::new(LocalIndex::load(&dir.0.join(INDEX_PATH), &dir.0))
+    }
+
+    #[tokio::test]
+    async fn untracked_matching_file_is_recorded() {
+        let dir = TempDir::new("untracked-same");
+        let path = dir.0.join("a.txt");
+        std::fs::write(&path, "same").unwrap();
+        let expected = ByteBuf::from(hash_of(&path).await);
+        let local = local(&dir);
+
+        let r = local
+            .check_existing("a.txt", &path, Some(expected.to_vec()), &expected)
+            .await
+            .unwrap();
+        assert_eq!(r, None);
+        assert!(local.is_tracked("a.txt"));
+        assert!(local.is_verified("a.txt", &expected));
+    }
+
+    #[tokio::test]
+    async fn untracked_different_file_conflicts() {
+        let dir = TempDir::new("untracked-diff");
+        let path = dir.0.join("a.txt");
+        std::fs::write(&path, "mine").unwrap();
+        let current = hash_of(&path).await;
+        let expected = ByteBuf::from(vec![0; 32]);
+        let local = local(&dir);
+
+        let r = local.check_existing("a.txt", &path, Some(current), &expected);
+        assert_eq!(r.await.unwrap(), Some(true));
+        // 大小与清单不同时没有计算哈希
+        let r = local.check_existing("a.txt", &path, None, &expected);
+        assert_eq!(r.await.unwrap(), Some(true));
+        assert!(!local.is_tracked("a.txt"));
+    }
+
+    #[tokio::test]
+    async fn tracked_unmodified_file_is_replaced() {
+        let dir = TempDir::new("tracked-same");
+        let path = dir.0.join("a.txt");
+        std::fs::write(&path, "old").unwrap();
+        let local = local(&dir);
+        local.verified("a.txt", ByteBuf::from(hash_of(&path).await));
+
+        let expected = ByteBuf::from(vec![0; 32]);
+        let r = local.check_existing("a.txt", &path, None, &expected);
+        assert_eq!(r.await.unwrap(), Some(false));
+    }
+
+    #[tokio::test]
+    async fn tracked_modified_file_conflicts() {
+        let dir = TempDir::new("tracked-modified");
+        let path = dir.0.join("a.txt");
+        std::fs::write(&path, "old").unwrap();
+        let local = local(&dir);
+        local.verified("a.txt", ByteBuf::from(hash_of(&path).await));
+        std::fs::write(&path, "edited").unwrap();
+
+        let expected = ByteBuf::from(vec![0; 32]);
+        let r = local.check_existing("a.txt", &path, None, &expected);
+        assert_eq!(r.await.unwrap(), Some(true));
+    }
+
+    #[tokio::test]
+    async fn touched_file_is_not_modified() {
+        let dir = TempDir::new("tracked-touched");
+        let path = dir.0.join("a.txt");
+        std::fs::write(&path, "old").unwrap();
+        let local = local(&dir);
+        local.verified("a.txt", ByteBuf::from(hash_of(&path).await));
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(3600))
+            .unwrap();
+        drop(file);
+
+        let expected = ByteBuf::from(vec![0; 32]);
+        let r = local.check_existing("a.txt", &path, None, &expected);
+        assert_eq!(r.await.unwrap(), Some(false));
+    }
+}
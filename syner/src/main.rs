@@ -2,29 +2,61 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 
-use std::{collections::HashMap, error::Error, fs, path::PathBuf, str::FromStr, time::Instant};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Instant,
+};
 
 mod boxed_ptr;
 mod client_model;
+mod conflict;
+mod local_index;
+mod peer;
+mod protected;
+mod proxy;
+mod self_update;
+mod servers;
 mod utils;
 mod winit_helper;
 use anyhow::anyhow;
 use boxed_ptr::*;
 use client_model::*;
+use conflict::*;
 use futures_lite::AsyncReadExt;
-use model::ManifestItem;
+use local_index::*;
+use model::{
+    calc_hash, platform, run_hook, ClientUpdate, ItemOutcome, ManifestItem, SyncReport,
+    SyncReportItem, CLIENT_ID_HEADER, CLIENT_VERSION_HEADER, HASH_HEADER, MANIFEST_FORMAT,
+    MANIFEST_FORMAT_HEADER, PEER_PORT_HEADER, UPDATE_HEADER, VERSION_HEADER,
+};
+use peer::*;
+use protected::*;
+use proxy::*;
+use self_update::*;
+use servers::*;
 use sha3::{Digest, Sha3_256};
 use slint::{ModelRc, SharedString, ToSharedString, VecModel, Weak};
 use tokio::{io::AsyncWriteExt, task::JoinSet};
 use url::Url;
 use utils::*;
+use uuid::Uuid;
 use winit_helper::*;
 
 slint::include_modules!();
 
 const CONFIG_PATH: &'static str = "syner.toml";
 
+/// 删除方式为重命名时追加的后缀
+const REMOVED_EXT: &str = "del";
+
 fn main() -> anyhow::Result<()> {
+    cleanup();
+
     let mut config_path = std::env::current_exe()?;
     config_path.pop();
     config_path.push(CONFIG_PATH);
@@ -33,17 +65,23 @@ fn main() -> anyhow::Result<()> {
     let config_data = config_data_ptr.as_mut();
 
     if !fs::exists(&config_path)? {
-        if !setup_window(&config_data_ptr, config_path)? {
+        if !setup_window(&config_data_ptr, config_path.clone())? {
             return Ok(());
         }
     } else {
-        let config_str = fs::read_to_string(config_path)?;
+        let config_str = fs::read_to_string(&config_path)?;
         *config_data = toml::from_str(&config_str)?;
     }
 
+    if config_data.client_id.is_none() {
+        config_data.client_id = Some(Uuid::new_v4());
+        fs::write(&config_path, toml::to_string_pretty(config_data)?)?;
+    }
+
     main_window(&config_data_ptr)?;
 
     drop(config_data_ptr);
+    relaunch_if_updated()?;
     Ok(())
 }
 
@@ -85,6 +123,27 @@ fn setup_window(config_data_ptr: &BoxPtr<Config>, config_path: PathBuf) -> anyho
                 success = false
             }
         }
+        let proxy = config.proxy.trim();
+        match check_proxy(proxy) {
+            Ok(()) => {
+                ui2.invoke_set_proxy_error("".into());
+                config_data.proxy.url = proxy.to_string();
+            }
+            Err(e) => {
+                ui2.invoke_set_proxy_error(format!("不正确的代理: {e}").into());
+                success = false
+            }
+        }
+        match parse_protected(config.protected_paths.as_str()) {
+            Ok(protected) => {
+                ui2.invoke_set_protected_error("".into());
+                config_data.protected = protected;
+            }
+            Err(e) => {
+                ui2.invoke_set_protected_error(format!("不正确的路径: {e}").into());
+                success = false
+            }
+        }
         success
     });
     let config_data = config_data_ptr.as_mut();
@@ -136,15 +195,68 @@ fn main_window(config_data_ptr: &BoxPtr<Config>) -> anyhow::Result<()> {
     ui.invoke_show();
     set_blur_tab(ui.window());
 
+    let share = if config_data.peer.enabled {
+        let local = LocalFiles::new(LocalIndex::load(&index_path()?, &config_data.cwd));
+        let share = Arc::new(PeerShare::new(
+            Arc::new(local),
+            config_data.peer.max_uploads,
+        ));
+        let port = config_data.peer.port;
+        let client_id = config_data.client_id.unwrap_or_default();
+        let s = share.clone();
+        rt.spawn(async move {
+            if let Err(e) = serve(port, client_id, s).await {
+                println!("Peer sharing failed {e:?}");
+            }
+        });
+        Some(share)
+    } else {
+        None
+    };
+    let servers = Arc::new(Servers::new(config_data, http_client(config_data)?));
+    let conflicts = Arc::new(Conflicts::new(&config_data.conflict)?);
+
+    {
+        let conflicts = conflicts.clone();
+        ui.on_resolve_conflict(move |choice| conflicts.answer(choice));
+    }
+
+    {
+        let config = config_data_ptr.ptr();
+        let ui2 = ui_ptr.as_mut();
+        ui.on_launch(move || match launch_program(config.as_ref()) {
+            Ok(()) => ui2.hide().unwrap(),
+            Err(e) => {
+                println!("Launch failed {e:?}");
+                ui2.invoke_set_launch_error(format!("启动失败：{e}").into());
+            }
+        });
+    }
+
     {
         let config = config_data_ptr.ptr();
         let ui = ui_ptr.as_ref().as_weak();
         let ui2 = ui_ptr.as_ref().as_weak();
         let manifest_ptr = manifest_ptr.ptr();
         rt.spawn(async move {
-            let r = req_manifest(config.as_ref(), manifest_ptr.as_mut()).await;
+            let start = Instant::now();
+            let r = req_manifest(config.as_ref(), &servers, manifest_ptr.as_mut()).await;
             match r {
-                Ok(_) => {
+                Ok((manifest_version, update)) => {
+                    // 先更新同步器，新程序启动后再同步内容
+                    match check_update(config.as_ref(), &servers, update).await {
+                        Ok(true) => {
+                            tokio::task::spawn_blocking(move || {
+                                ui.upgrade_in_event_loop(move |ui| ui.hide().unwrap())
+                                    .unwrap()
+                            })
+                            .await
+                            .unwrap();
+                            return;
+                        }
+                        Ok(false) => {}
+                        Err(e) => println!("Self update failed {e:?}"),
+                    }
                     let model: VecModel<_> = manifest_ptr
                         .as_ref()
                         .iter()
@@ -153,7 +265,9 @@ fn main_window(config_data_ptr: &BoxPtr<Config>) -> anyhow::Result<()> {
                             index: i as i32,
                             op: match a.op {
                                 model::ItemOp::Sync => ModelItemOp::Sync,
-                                model::ItemOp::Remove => ModelItemOp::Remove,
+                                model::ItemOp::Remove | model::ItemOp::RemoveGlob => {
+                                    ModelItemOp::Remove
+                                }
                             },
                             path: a.path.1.clone(),
                             cur: "".into(),
@@ -164,11 +278,13 @@ fn main_window(config_data_ptr: &BoxPtr<Config>) -> anyhow::Result<()> {
                         })
                         .collect();
                     let model = SendT(ModelRc::new(model));
+                    let server = servers.active().to_shared_string();
                     {
                         let ui = ui.clone();
                         tokio::task::spawn_blocking(move || {
                             ui.upgrade_in_event_loop(move |ui| {
                                 let model = model;
+                                ui.invoke_set_server(server);
                                 ui.invoke_set_manifest_ok(model.0);
                             })
                             .unwrap();
@@ -176,11 +292,63 @@ fn main_window(config_data_ptr: &BoxPtr<Config>) -> anyhow::Result<()> {
                         .await
                         .unwrap();
                     }
-                    match do_sync(ui.clone(), config.as_ref(), manifest_ptr.as_ref()).await {
-                        Ok(_) => {
+                    let r = do_sync(
+                        ui.clone(),
+                        config.as_ref(),
+                        manifest_ptr.as_ref(),
+                        share,
+                        servers.clone(),
+                        conflicts,
+                        manifest_version.as_deref(),
+                    )
+                    .await;
+                    send_report(config.as_ref(), &servers, manifest_version, start, &r).await;
+                    match r {
+                        Ok(summary) => {
+                            // 有文件同步失败时程序可能不完整，不允许启动
+                            let launch = &config.as_ref().launch;
+                            let failed = summary
+                                .items
+                                .iter()
+                                .filter(|a| a.outcome == ItemOutcome::Failed)
+                                .count();
+                            let mut error = String::new();
+                            let mut launched = false;
+                            if !launch.program.is_empty() {
+                                if failed > 0 {
+                                    error = format!("有 {failed} 个文件同步失败，不能启动");
+                                } else if launch.auto {
+                                    match launch_program(config.as_ref()) {
+                                        Ok(()) => launched = true,
+                                        Err(e) => {
+                                            println!("Launch failed {e:?}");
+                                            error = format!("启动失败：{e}");
+                                        }
+                                    }
+                                }
+                            }
+                            let program = launch.program.to_shared_string();
+                            let error = SharedString::from(error);
+                            let hook_error = SharedString::from(summary.hook_errors.join("\n"));
+                            let conflicts: Vec<String> = summary
+                                .conflicts
+                                .iter()
+                                .map(|(path, policy)| {
+                                    format!("{path}：{}", conflict_label(*policy))
+                                })
+                                .collect();
+                            let conflicts = SharedString::from(conflicts.join("\n"));
                             tokio::task::spawn_blocking(move || {
                                 ui.upgrade_in_event_loop(move |ui| {
                                     ui.invoke_set_sync_ok();
+                                    ui.invoke_set_hook_error(hook_error);
+                                    ui.invoke_set_conflicts(conflicts);
+                                    if launched {
+                                        ui.hide().unwrap();
+                                    } else {
+                                        ui.invoke_set_launch(program, failed == 0);
+                                        ui.invoke_set_launch_error(error);
+                                    }
                                 })
                                 .unwrap()
                             })
@@ -237,17 +405,225 @@ fn main_window(config_data_ptr: &BoxPtr<Config>) -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn req_manifest(config: &Config, manifest_ptr: &mut ClientManifest) -> anyhow::Result<()> {
-    let server = &config.server;
+/// 启动同步完成后的程序，不等待其退出
+fn launch_program(config: &Config) -> anyhow::Result<()> {
+    let launch = &config.launch;
+    let program = config.cwd.join(&launch.program);
+    let dir = if launch.dir.is_empty() {
+        program
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| config.cwd.clone())
+    } else {
+        config.cwd.join(&launch.dir)
+    };
+    println!("Launch {program:?} {:?} in {dir:?}", launch.args);
+    std::process::Command::new(&program)
+        .args(&launch.args)
+        .current_dir(&dir)
+        .spawn()?;
+    Ok(())
+}
+
+/// 让服务器能识别发出请求的客户端
+fn client_headers(config: &Config, req: surf::RequestBuilder) -> surf::RequestBuilder {
+    let req = req
+        .header(
+            CLIENT_ID_HEADER,
+            config.client_id.unwrap_or_default().to_string(),
+        )
+        .header(CLIENT_VERSION_HEADER, env!("CARGO_PKG_VERSION"))
+        .header(MANIFEST_FORMAT_HEADER, MANIFEST_FORMAT.to_string());
+    if config.peer.enabled {
+        req.header(PEER_PORT_HEADER, config.peer.port.to_string())
+    } else {
+        req
+    }
+}
+
+const MAX_RETRY: usize = 5;
+
+/// 发送请求，服务器限流（429 / 503）时按 Retry-After 等待后重试，其他错误状态直接返回错误
+async fn send_request(
+    config: &Config,
+    req: impl Fn() -> anyhow::Result<surf::RequestBuilder>,
+) -> anyhow::Result<surf::Response> {
+    let mut retry = 0;
+    loop {
+        let res = client_headers(config, req()?)
+            .await
+            .map_err(|e| anyhow!(e))?;
+        let status = res.status();
+        if status.is_success() {
+            return Ok(res);
+        }
+        let limited = status == surf::StatusCode::TooManyRequests
+            || status == surf::StatusCode::ServiceUnavailable;
+        if !limited || retry >= MAX_RETRY {
+            return Err(StatusError(status).into());
+        }
+        retry += 1;
+        let wait = res
+            .header("Retry-After")
+            .and_then(|a| a.as_str().trim().parse::<u64>().ok())
+            .unwrap_or(1)
+            .min(60);
+        println!("Server busy ({status}), retry in {wait}s");
+        tokio::time::sleep(std::time::Duration::from_secs(wait)).await;
+    }
+}
+
+/// 服务器返回的错误状态
+#[derive(Debug)]
+struct StatusError(surf::StatusCode);
+
+impl std::fmt::Display for StatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "server returned {}", self.0)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+/// 按哈希下载文件内容，服务器没有 /blob 接口时按路径下载
+async fn req_content(
+    config: &Config,
+    client: &surf::Client,
+    server: &Url,
+    item: &ClientManifestItem,
+) -> anyhow::Result<surf::Response> {
+    let hash: String = item.hash.iter().map(|b| format!("{b:02x}")).collect();
+    let api = server.join(&format!("blob/{hash}"))?;
+    match send_request(config, || Ok(client.get(api.clone()))).await {
+        Err(e)
+            if e.downcast_ref::<StatusError>()
+                .is_some_and(|a| a.0.is_client_error()) =>
+        {
+            let api = server.join(&format!("content/{}", item.path.2))?;
+            send_request(config, || Ok(client.get(api.clone()))).await
+        }
+        res => res,
+    }
+}
+
+/// 每个文件最多尝试的其他客户端数量，均失败时从服务器下载
+const MAX_PEER_TRIES: usize = 2;
 
-    let mut res = surf::get(server.join("manifest")?)
+/// 等待其他客户端响应的时间
+const PEER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// 合并服务器提供的列表与广播发现的其他客户端
+async fn find_peers(config: &Config, servers: &Servers) -> Vec<Url> {
+    let mut peers: Vec<std::net::SocketAddr> = vec![];
+    let res = async {
+        let server = servers.active();
+        let client = servers.client();
+        let mut res = send_request(config, || Ok(client.get(server.join("peers")?))).await?;
+        let blob = res.body_bytes().await.map_err(|e| anyhow!(e))?;
+        anyhow::Result::<Vec<std::net::SocketAddr>>::Ok(rmp_serde::from_slice(&blob)?)
+    }
+    .await;
+    match res {
+        Ok(list) => peers.extend(list),
+        Err(e) => println!("Request peers failed {e:?}"),
+    }
+    if config.peer.broadcast {
+        match discover(config.peer.port, config.client_id.unwrap_or_default()).await {
+            Ok(list) => peers.extend(list),
+            Err(e) => println!("Discover peers failed {e:?}"),
+        }
+    }
+    peers.sort();
+    peers.dedup();
+    println!("Found {} peers", peers.len());
+    peers
+        .into_iter()
+        .filter_map(|a| Url::parse(&format!("http://{a}/")).ok())
+        .collect()
+}
+
+/// 从其他客户端下载，对方记录的哈希与清单不一致时不下载，连接失败或超时时标记为不可用
+async fn req_peer_content(
+    peers: &Peers,
+    peer: &Url,
+    item: &ClientManifestItem,
+) -> anyhow::Result<surf::Response> {
+    let api = peer.join(&format!("content/{}", item.path.2))?;
+    let res = match tokio::time::timeout(PEER_TIMEOUT, surf::get(api)).await {
+        Ok(Ok(res)) => res,
+        Ok(Err(e)) => {
+            peers.failed(peer);
+            return Err(anyhow!(e));
+        }
+        Err(e) => {
+            peers.failed(peer);
+            return Err(e.into());
+        }
+    };
+    if !res.status().is_success() {
+        return Err(StatusError(res.status()).into());
+    }
+    let hash: String = item.hash.iter().map(|b| format!("{b:02x}")).collect();
+    if res.header(HASH_HEADER).map(|a| a.as_str()) != Some(hash.as_str()) {
+        return Err(anyhow!("peer has a different version"));
+    }
+    Ok(res)
+}
+
+/// 等待服务器返回清单的时间，超时视为不可用
+const MANIFEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// 按顺序向各服务器请求清单，第一个成功的服务器作为本次同步的服务器，
+/// 返回清单版本与清单中发布的本平台程序
+async fn req_manifest(
+    config: &Config,
+    servers: &Servers,
+    manifest_ptr: &mut ClientManifest,
+) -> anyhow::Result<(Option<String>, Option<ClientUpdate>)> {
+    let client = servers.client();
+    let mut errors = vec![];
+    let mut found = None;
+    for server in servers.all() {
+        let res = tokio::time::timeout(MANIFEST_TIMEOUT, async {
+            let mut res = send_request(config, || Ok(client.get(server.join("manifest")?))).await?;
+            let version = res.header(VERSION_HEADER).map(|a| a.as_str().to_string());
+            let update = res
+                .header(UPDATE_HEADER)
+                .and_then(|a| ClientUpdate::from_header(a.as_str(), &platform()));
+            let manifest_bytes = res.body_bytes().await.map_err(|e| anyhow!(e))?;
+            anyhow::Result::<_>::Ok((version, update, manifest_bytes))
+        })
         .await
-        .map_err(|e| anyhow!(e))?;
-    let manifest_bytes = res.body_bytes().await.map_err(|e| anyhow!(e))?;
+        .map_err(|_| anyhow!("timed out"))
+        .and_then(|a| a);
+        match res {
+            Ok(res) => {
+                servers.select(&server);
+                found = Some(res);
+                break;
+            }
+            Err(e) => {
+                println!("Request manifest from {server} failed {e:?}");
+                servers.failed(&server);
+                errors.push(format!("{server}: {e:?}"));
+            }
+        }
+    }
+    let Some((version, update, manifest_bytes)) = found else {
+        return Err(anyhow!("所有服务器均不可用\n{}", errors.join("\n")));
+    };
     let manifest: HashMap<String, ManifestItem> = rmp_serde::from_slice(&manifest_bytes)?;
 
+    // 路径只在这里检查一次，之后的保护匹配与写入都使用规范化后的路径
     *manifest_ptr = manifest
         .into_iter()
+        .filter_map(|a| match normalize_manifest_path(&a.0) {
+            Ok(path) => Some((path, a.1)),
+            Err(e) => {
+                println!("Skip manifest item {e:?}");
+                None
+            }
+        })
         .map(|a| ClientManifestItem {
             path: ((&a.0).into(), (&a.0).into(), a.0),
             op: a.1 .0,
@@ -256,14 +632,58 @@ async fn req_manifest(config: &Config, manifest_ptr: &mut ClientManifest) -> any
         })
         .collect();
 
-    Ok(())
+    Ok((version, update))
+}
+
+/// 同步结束后把结果上报给服务器，上报失败不影响同步结果
+async fn send_report(
+    config: &Config,
+    servers: &Servers,
+    manifest_version: Option<String>,
+    start: Instant,
+    result: &anyhow::Result<SyncSummary>,
+) {
+    let (items, bytes, error) = match result {
+        Ok(summary) => (summary.items.clone(), summary.bytes, None),
+        Err(e) => (vec![], 0, Some(format!("{e:?}"))),
+    };
+    let report = SyncReport {
+        client_id: config.client_id.unwrap_or_default(),
+        client_version: env!("CARGO_PKG_VERSION").to_string(),
+        manifest_version,
+        duration_ms: start.elapsed().as_millis() as u64,
+        bytes,
+        items,
+        error,
+    };
+    if let Err(e) = async {
+        let body = rmp_serde::to_vec(&report)?;
+        let server = servers.active();
+        send_request(config, || {
+            Ok(servers
+                .client()
+                .post(server.join("report")?)
+                .header("Content-Type", "application/msgpack")
+                .body_bytes(&body))
+        })
+        .await?;
+        anyhow::Result::<()>::Ok(())
+    }
+    .await
+    {
+        println!("Send report failed {e:?}");
+    }
 }
 
 async fn do_sync(
     ui: Weak<AppWindow>,
     config: &'static Config,
     manifest_ptr: &'static ClientManifest,
-) -> anyhow::Result<()> {
+    share: Option<Arc<PeerShare>>,
+    servers: Arc<Servers>,
+    conflicts: Arc<Conflicts>,
+    version: Option<&str>,
+) -> anyhow::Result<SyncSummary> {
     let mut js = JoinSet::new();
 
     {
@@ -276,13 +696,60 @@ async fn do_sync(
         .await??;
     }
 
+    let mut summary = SyncSummary::default();
+    let index_path = index_path()?;
+    let index = LocalIndex::load(&index_path, &config.cwd);
+    let protected = Arc::new(Protected::new(&config.protected)?);
+
+    // 移动或替换任何文件之前执行
+    let hooks = &config.hooks;
+    if !hooks.before.is_empty() {
+        let pending = pending_paths(config, manifest_ptr, &index, &protected);
+        let res = sync_hook(config, &servers, "before", version, &pending, 0).await;
+        if let Err(e) = res {
+            if hooks.abort_on_failure {
+                return Err(e.context("同步前的命令失败，已取消同步"));
+            }
+            println!("Sync hook before failed {e:?}");
+            summary.hook_errors.push(format!("同步前的命令失败：{e}"));
+        }
+    }
+
+    let local = Arc::new(relocate(manifest_ptr, index, &protected).await);
+    if let Some(share) = &share {
+        share.replace(local.clone());
+    }
+    let peers = Arc::new(if config.peer.enabled {
+        Peers::new(find_peers(config, &servers).await)
+    } else {
+        Peers::default()
+    });
+
     for index in 0..manifest_ptr.len() {
         let ui = ui.clone();
+        let local = local.clone();
+        let peers = peers.clone();
+        let servers = servers.clone();
+        let conflicts = conflicts.clone();
+        let protected = protected.clone();
         js.spawn(async move {
             let manifest = &manifest_ptr[index];
 
-            match do_sync_item(index, ui.clone(), config, manifest).await {
-                Ok(_) => {
+            let (outcome, bytes, error) = match do_sync_item(
+                index,
+                ui.clone(),
+                config,
+                manifest_ptr,
+                &local,
+                &peers,
+                &servers,
+                &conflicts,
+                &protected,
+                manifest,
+            )
+            .await
+            {
+                Ok((outcome, bytes)) => {
                     println!("Finish {index}");
                     tokio::task::spawn_blocking(move || {
                         ui.upgrade_in_event_loop(move |ui| {
@@ -292,6 +759,7 @@ async fn do_sync(
                     })
                     .await
                     .unwrap();
+                    (outcome, bytes, None)
                 }
                 Err(e) => {
                     println!("Error {index} {e:?}");
@@ -303,15 +771,25 @@ async fn do_sync(
                     })
                     .await
                     .unwrap();
+                    (ItemOutcome::Failed, 0, Some(format!("{e:?}")))
                 }
             };
+            let item = SyncReportItem {
+                path: manifest.path.2.clone(),
+                op: manifest.op,
+                outcome,
+                error,
+            };
+            (item, bytes)
         });
     }
 
     let mut count = 0usize;
 
     while let Some(r) = js.join_next().await {
-        r?;
+        let (item, bytes) = r?;
+        summary.items.push(item);
+        summary.bytes += bytes;
 
         {
             count += 1;
@@ -327,26 +805,143 @@ async fn do_sync(
         }
     }
 
-    Ok(())
+    if let Err(e) = local.index.lock().unwrap().save(&index_path) {
+        println!("Save local index failed {e:?}");
+    }
+    summary.conflicts = conflicts.take();
+
+    if !hooks.after.is_empty() {
+        let changed: Vec<String> = summary
+            .items
+            .iter()
+            .filter(|a| matches!(a.outcome, ItemOutcome::Updated | ItemOutcome::Removed))
+            .map(|a| a.path.clone())
+            .collect();
+        let failed = summary
+            .items
+            .iter()
+            .filter(|a| a.outcome == ItemOutcome::Failed)
+            .count();
+        let res = sync_hook(config, &servers, "after", version, &changed, failed).await;
+        if let Err(e) = res {
+            if hooks.abort_on_failure {
+                return Err(e.context("同步后的命令失败"));
+            }
+            println!("Sync hook after failed {e:?}");
+            summary.hook_errors.push(format!("同步后的命令失败：{e}"));
+        }
+    }
+
+    Ok(summary)
+}
+
+/// 同步前还不知道哪些文件会改变，列出清单中与本地索引不一致的路径
+fn pending_paths(
+    config: &Config,
+    manifest: &ClientManifest,
+    index: &LocalIndex,
+    protected: &Protected,
+) -> Vec<String> {
+    manifest
+        .iter()
+        .filter(|a| a.op == model::ItemOp::RemoveGlob || !protected.contains(&a.path.2))
+        .filter(|a| match a.op {
+            model::ItemOp::Sync => index.verified_hash(&a.path.2) != Some(&a.hash),
+            model::ItemOp::Remove => config.cwd.join(&a.path.0).exists(),
+            model::ItemOp::RemoveGlob => true,
+        })
+        .map(|a| a.path.2.clone())
+        .collect()
+}
+
+/// 执行同步前后的命令，变化的路径见 `SYNER_CHANGED_FILE`
+async fn sync_hook(
+    config: &Config,
+    servers: &Servers,
+    stage: &str,
+    version: Option<&str>,
+    changed: &[String],
+    failed: usize,
+) -> anyhow::Result<()> {
+    let command = match stage {
+        "before" => &config.hooks.before,
+        _ => &config.hooks.after,
+    };
+    let envs = [
+        ("SYNER_STAGE", stage.to_string()),
+        ("SYNER_VERSION", version.unwrap_or_default().to_string()),
+        ("SYNER_SERVER", servers.active().to_string()),
+        ("SYNER_CWD", config.cwd.to_string_lossy().to_string()),
+        ("SYNER_FAILED_COUNT", failed.to_string()),
+    ];
+    println!(
+        "Run sync hook {stage} {command:?}, {} changed",
+        changed.len()
+    );
+    run_hook(command, &envs, changed).await
 }
 
+fn index_path() -> anyhow::Result<PathBuf> {
+    let mut path = std::env::current_exe()?;
+    path.pop();
+    path.push(INDEX_PATH);
+    Ok(path)
+}
+
+/// 返回该项的结果和下载的字节数
 async fn do_sync_item(
     index: usize,
     ui: Weak<AppWindow>,
     config: &Config,
+    manifest: &ClientManifest,
+    local: &LocalFiles,
+    peers: &Peers,
+    servers: &Servers,
+    conflicts: &Conflicts,
+    protected: &Protected,
     item: &ClientManifestItem,
-) -> anyhow::Result<()> {
-    let server = &config.server;
-
+) -> anyhow::Result<(ItemOutcome, u64)> {
     let mut path = config.cwd.clone();
     path.push(&item.path.0);
-    let mut dir = path.clone();
-    dir.pop();
-    tokio::fs::create_dir_all(&dir).await?;
+
+    // 受保护的路径不做任何改动，删除用的 glob 只跳过其中受保护的文件
+    if item.op != model::ItemOp::RemoveGlob && protected.contains(&item.path.2) {
+        println!("Skip protected {}", item.path.2);
+        tokio::task::spawn_blocking(move || {
+            ui.upgrade_in_event_loop(move |ui| {
+                ui.invoke_set_manifest_item_state(index as i32, ModelItemState::Protected);
+            })
+        })
+        .await??;
+        return Ok((ItemOutcome::Unchanged, 0));
+    }
 
     match item.op {
         model::ItemOp::Sync => {
+            let mut dir = path.clone();
+            dir.pop();
+            tokio::fs::create_dir_all(&dir).await?;
+
+            let unchanged = if local.relocated.contains(&item.path.2) {
+                ItemOutcome::Updated
+            } else {
+                ItemOutcome::Unchanged
+            };
+
+            // 索引中的哈希在文件大小和修改时间不变时可信，不需要重新计算
+            if local.is_verified(&item.path.2, &item.hash) {
+                tokio::task::spawn_blocking(move || {
+                    ui.upgrade_in_event_loop(move |ui| {
+                        ui.invoke_set_manifest_item_state(index as i32, ModelItemState::NoOp);
+                    })
+                })
+                .await??;
+                return Ok((unchanged, 0));
+            }
+
+            let mut is_conflict = false;
             if tokio::fs::try_exists(&path).await? {
+                let mut local_hash = None;
                 {
                     let ui = ui.clone();
                     tokio::task::spawn_blocking(move || {
@@ -417,7 +1012,16 @@ async fn do_sync_item(
                         .await??
                     };
 
-                    if item.hash == hash {
+                    local_hash = Some(hash);
+                }
+
+                // 与清单相同的文件直接记录，
+                // 上次同步后在本地修改过的文件和不是同步器写入的文件按冲突策略处理
+                match local
+                    .check_existing(&item.path.2, &path, local_hash, &item.hash)
+                    .await?
+                {
+                    None => {
                         tokio::task::spawn_blocking(move || {
                             ui.upgrade_in_event_loop(move |ui| {
                                 ui.invoke_set_manifest_item_state(
@@ -427,79 +1031,71 @@ async fn do_sync_item(
                             })
                         })
                         .await??;
-                        return Ok(());
+                        return Ok((unchanged, 0));
                     }
+                    Some(conflict) => is_conflict = conflict,
                 }
             }
 
+            if is_conflict && !resolve_conflict(ui.clone(), config, conflicts, &item.path.2).await?
             {
-                let ui = ui.clone();
                 tokio::task::spawn_blocking(move || {
                     ui.upgrade_in_event_loop(move |ui| {
-                        ui.invoke_set_manifest_item_state(index as i32, ModelItemState::Sync);
+                        ui.invoke_set_manifest_item_state(index as i32, ModelItemState::Conflict);
                     })
                 })
                 .await??;
+                return Ok((ItemOutcome::Unchanged, 0));
             }
 
-            let api = server.join(&format!("content/{}", item.path.2))?;
-            let mut res = surf::get(api).await.map_err(|e| anyhow!(e))?;
-            let content_length: Option<usize> = res
-                .header("Content-Length")
-                .and_then(|a| a.as_str().parse().ok());
-            let mut body = res.take_body();
-            let total_size = content_length.or(body.len());
-            if let Some(total_size) = total_size {
+            {
                 let ui = ui.clone();
                 tokio::task::spawn_blocking(move || {
                     ui.upgrade_in_event_loop(move |ui| {
-                        ui.invoke_set_manifest_item_len(
-                            index as i32,
-                            total_size.to_shared_string(),
-                        );
+                        ui.invoke_set_manifest_item_state(index as i32, ModelItemState::Sync);
                     })
                 })
                 .await??;
             }
-            let mut file = tokio::fs::File::create(&path).await?;
-            tokio::spawn(async move {
-                let mut buffer: [u8; 4096] = [0; 4096];
-                let mut size = 0;
-                let mut start = Instant::now();
-                loop {
-                    let len = body.read(&mut buffer).await?;
-                    if len == 0 {
+
+            // 内容相同的文件已由其他任务下载时直接复制
+            let mut downloaded = local.claim(&item.hash).await;
+            if let Some(src) = downloaded.as_ref() {
+                tokio::fs::copy(config.cwd.join(src), &path).await?;
+                println!("Copy {src} => {}", item.path.2);
+                local.verified(&item.path.2, item.hash.clone());
+                return Ok((ItemOutcome::Updated, 0));
+            }
+
+            // 先下载到临时文件，哈希与清单一致后才替换本地文件
+            let part = part_path(&path);
+            let mut size = None;
+            for peer in peers.candidates(index, MAX_PEER_TRIES) {
+                let ui2 = ui.clone();
+                let res = async {
+                    let res = req_peer_content(peers, &peer, item).await?;
+                    download(index, ui2, res, &part, &item.hash).await
+                }
+                .await;
+                match res {
+                    Ok(len) => {
+                        println!("Sync {index} from peer {peer}");
+                        size = Some(len);
                         break;
                     }
-                    file.write(&mut buffer[..len]).await?;
-                    size += len;
-                    if let Some(total_size) = total_size {
-                        let now = Instant::now();
-                        if (now - start).as_micros() > 500 {
-                            start = now;
-                            let p = (size as f64 / total_size as f64) as f32;
-                            let pp = p * 100f32;
-                            println!("Sync {index} : {pp:.2}% ; {size} / {total_size}");
-                            let ui = ui.clone();
-                            tokio::task::spawn_blocking(move || {
-                                ui.upgrade_in_event_loop(move |ui| {
-                                    ui.invoke_set_manifest_item_cur(
-                                        index as i32,
-                                        size.to_shared_string(),
-                                        p,
-                                        format!("{pp:.2}").into(),
-                                    );
-                                })
-                            })
-                            .await??;
-                        }
-                    }
+                    Err(e) => println!("Sync {index} from peer {peer} failed {e:?}"),
                 }
-                println!("Sync {index} finish");
-                return anyhow::Result::<()>::Ok(());
-            })
-            .await??;
-            return Ok(());
+            }
+            let size = match size {
+                Some(size) => size,
+                None => {
+                    download_from_servers(index, ui.clone(), config, servers, item, &part).await?
+                }
+            };
+            tokio::fs::rename(&part, &path).await?;
+            local.verified(&item.path.2, item.hash.clone());
+            *downloaded = Some(item.path.2.clone());
+            return Ok((ItemOutcome::Updated, size));
         }
         model::ItemOp::Remove => {
             if !tokio::fs::try_exists(&path).await? {
@@ -509,29 +1105,257 @@ async fn do_sync_item(
                     })
                 })
                 .await??;
-                return Ok(());
+                return Ok((ItemOutcome::Unchanged, 0));
+            }
+
+            if is_modified(local, &item.path.2, &path).await?
+                && !resolve_conflict(ui.clone(), config, conflicts, &item.path.2).await?
+            {
+                tokio::task::spawn_blocking(move || {
+                    ui.upgrade_in_event_loop(move |ui| {
+                        ui.invoke_set_manifest_item_state(index as i32, ModelItemState::Conflict);
+                    })
+                })
+                .await??;
+                return Ok((ItemOutcome::Unchanged, 0));
             }
 
-            match config.delete_mode {
-                DeleteMode::Rename => {
-                    let mut dst = path.clone();
-                    dst.set_extension(format!(
-                        "{}.{}",
-                        dst.extension()
-                            .map(|a| a.to_string_lossy())
-                            .as_deref()
-                            .unwrap_or(""),
-                        "del" // todo config
-                    ));
-                    // println!("{dst:?}");
-                    tokio::fs::rename(&path, dst).await?;
-                    return Ok(());
+            remove_local(config, &path).await?;
+            local.removed(&item.path.2);
+            return Ok((ItemOutcome::Removed, 0));
+        }
+        model::ItemOp::RemoveGlob => {
+            let root = config.cwd.clone();
+            let pattern = item.path.2.clone();
+            let files = tokio::task::spawn_blocking(move || expand_glob(&root, &pattern)).await??;
+
+            // 清单中要同步的文件不删除
+            let synced: HashSet<&str> = manifest
+                .iter()
+                .filter(|a| a.op == model::ItemOp::Sync)
+                .map(|a| a.path.2.as_str())
+                .collect();
+            let mut removed = 0;
+            let mut skipped = 0;
+            let mut kept = 0;
+            let backup_dir = format!("{BACKUP_DIR}/");
+            for rel in files
+                .iter()
+                .filter(|a| !synced.contains(a.as_str()) && !a.starts_with(&backup_dir))
+            {
+                if config.delete_mode == DeleteMode::Rename
+                    && rel.ends_with(&format!(".{REMOVED_EXT}"))
+                {
+                    continue;
+                }
+                if protected.contains(rel) {
+                    println!("Skip protected {rel} by {}", item.path.2);
+                    skipped += 1;
+                    continue;
                 }
-                DeleteMode::Delete => {
-                    tokio::fs::remove_file(&path).await?;
-                    return Ok(());
+                let path = config.cwd.join(rel);
+                if is_modified(local, rel, &path).await?
+                    && !resolve_conflict(ui.clone(), config, conflicts, rel).await?
+                {
+                    kept += 1;
+                    continue;
                 }
+                println!("Remove {rel} by {}", item.path.2);
+                remove_local(config, &path).await?;
+                local.removed(rel);
+                removed += 1;
             }
+
+            if removed == 0 {
+                let state = if kept > 0 {
+                    ModelItemState::Conflict
+                } else if skipped > 0 {
+                    ModelItemState::Protected
+                } else {
+                    ModelItemState::NoOp
+                };
+                tokio::task::spawn_blocking(move || {
+                    ui.upgrade_in_event_loop(move |ui| {
+                        ui.invoke_set_manifest_item_state(index as i32, state);
+                    })
+                })
+                .await??;
+                return Ok((ItemOutcome::Unchanged, 0));
+            }
+            return Ok((ItemOutcome::Removed, 0));
+        }
+    }
+}
+
+/// 依次尝试各服务器，当前服务器失败时换到下一个，并在界面上显示新的服务器
+async fn download_from_servers(
+    index: usize,
+    ui: Weak<AppWindow>,
+    config: &Config,
+    servers: &Servers,
+    item: &ClientManifestItem,
+    part: &Path,
+) -> anyhow::Result<u64> {
+    let mut last_error = None;
+    for server in servers.candidates() {
+        // Weak<AppWindow> 不是 Sync，不能以引用跨 await 持有
+        let ui2 = ui.clone();
+        let res = async {
+            let res = req_content(config, servers.client(), &server, item).await?;
+            download(index, ui2, res, part, &item.hash).await
+        }
+        .await;
+        match res {
+            Ok(size) => {
+                if servers.succeeded(&server) {
+                    println!("Switch to server {server}");
+                    let server = server.to_shared_string();
+                    tokio::task::spawn_blocking(move || {
+                        ui.upgrade_in_event_loop(move |ui| {
+                            ui.invoke_set_server(server);
+                        })
+                    })
+                    .await??;
+                }
+                return Ok(size);
+            }
+            Err(e) => {
+                println!("Sync {index} from {server} failed {e:?}");
+                // 文件不存在等请求错误不代表服务器不可用
+                let client_error = e
+                    .downcast_ref::<StatusError>()
+                    .is_some_and(|a| a.0.is_client_error());
+                if !client_error {
+                    servers.failed(&server);
+                }
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow!("no server available")))
+}
+
+/// 下载中的文件名
+fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+/// 把响应内容写入 `part`，哈希与清单不一致时删除并返回错误
+async fn download(
+    index: usize,
+    ui: Weak<AppWindow>,
+    mut res: surf::Response,
+    part: &Path,
+    expected: &[u8],
+) -> anyhow::Result<u64> {
+    let content_length: Option<usize> = res
+        .header("Content-Length")
+        .and_then(|a| a.as_str().parse().ok());
+    let mut body = res.take_body();
+    let total_size = content_length.or(body.len());
+    if let Some(total_size) = total_size {
+        let ui = ui.clone();
+        tokio::task::spawn_blocking(move || {
+            ui.upgrade_in_event_loop(move |ui| {
+                ui.invoke_set_manifest_item_len(index as i32, total_size.to_shared_string());
+            })
+        })
+        .await??;
+    }
+    let mut file = tokio::fs::File::create(part).await?;
+    let (size, hash) = tokio::spawn(async move {
+        let mut buffer: [u8; 4096] = [0; 4096];
+        let mut size = 0;
+        let mut start = Instant::now();
+        let mut hasher = Sha3_256::new();
+        loop {
+            let len = body.read(&mut buffer).await?;
+            if len == 0 {
+                break;
+            }
+            file.write_all(&buffer[..len]).await?;
+            hasher.update(&buffer[..len]);
+            size += len;
+            if let Some(total_size) = total_size {
+                let now = Instant::now();
+                if (now - start).as_micros() > 500 {
+                    start = now;
+                    let p = (size as f64 / total_size as f64) as f32;
+                    let pp = p * 100f32;
+                    println!("Sync {index} : {pp:.2}% ; {size} / {total_size}");
+                    let ui = ui.clone();
+                    tokio::task::spawn_blocking(move || {
+                        ui.upgrade_in_event_loop(move |ui| {
+                            ui.invoke_set_manifest_item_cur(
+                                index as i32,
+                                size.to_shared_string(),
+                                p,
+                                format!("{pp:.2}").into(),
+                            );
+                        })
+                    })
+                    .await??;
+                }
+            }
+        }
+        file.flush().await?;
+        println!("Sync {index} finish");
+        return anyhow::Result::<_>::Ok((size as u64, hasher.finalize().to_vec()));
+    })
+    .await??;
+    if expected != hash {
+        tokio::fs::remove_file(part).await?;
+        return Err(anyhow!("hash mismatch after download"));
+    }
+    Ok(size)
+}
+
+/// 本地文件在上次同步后被修改过，只改动修改时间的不算
+async fn is_modified(local: &LocalFiles, rel: &str, path: &Path) -> anyhow::Result<bool> {
+    let Some(synced) = local.modified_hash(rel) else {
+        return Ok(false);
+    };
+    let current = calc_hash(tokio::fs::File::open(path).await?).await?;
+    Ok(*synced != current)
+}
+
+/// 按冲突策略处理将被覆盖或删除的本地文件，返回是否继续
+async fn resolve_conflict(
+    ui: Weak<AppWindow>,
+    config: &Config,
+    conflicts: &Conflicts,
+    rel: &str,
+) -> anyhow::Result<bool> {
+    match conflicts.resolve(ui, rel).await {
+        ConflictPolicy::Skip => Ok(false),
+        ConflictPolicy::Backup => {
+            backup(&config.cwd, rel).await?;
+            Ok(true)
+        }
+        ConflictPolicy::Overwrite | ConflictPolicy::Ask => Ok(true),
+    }
+}
+
+/// 按配置的删除方式删除本地文件
+async fn remove_local(config: &Config, path: &Path) -> anyhow::Result<()> {
+    match config.delete_mode {
+        DeleteMode::Rename => {
+            let mut dst = path.to_path_buf();
+            dst.set_extension(format!(
+                "{}.{}",
+                dst.extension()
+                    .map(|a| a.to_string_lossy())
+                    .as_deref()
+                    .unwrap_or(""),
+                REMOVED_EXT
+            ));
+            tokio::fs::rename(path, dst).await?;
+        }
+        DeleteMode::Delete => {
+            tokio::fs::remove_file(path).await?;
         }
     }
+    Ok(())
 }
@@ -0,0 +1,249 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use model::HASH_HEADER;
+use percent_encoding::percent_decode_str;
+use tokio::net::UdpSocket;
+use tokio::sync::Semaphore;
+use tokio_util::io::ReaderStream;
+use url::Url;
+use uuid::Uuid;
+use warp::{
+    filters::path::Tail,
+    http::{header::*, Response, StatusCode},
+    hyper::Body,
+    reject::Rejection,
+    Filter,
+};
+
+use crate::local_index::LocalFiles;
+
+const DISCOVER_REQUEST: &[u8] = b"syner-peer?";
+
+/// 回复格式为 `syner-peer <客户端 ID> <端口>`
+const DISCOVER_REPLY: &str = "syner-peer";
+
+/// 广播后等待回复的时间
+const DISCOVER_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// 向其他客户端提供的文件，同步开始后换成本次同步的状态
+#[derive(Debug)]
+pub struct PeerShare {
+    local: RwLock<Arc<LocalFiles>>,
+    uploads: Arc<Semaphore>,
+}
+
+impl PeerShare {
+    pub fn new(local: Arc<LocalFiles>, max_uploads: usize) -> Self {
+        Self {
+            local: RwLock::new(local),
+            uploads: Arc::new(Semaphore::new(max_uploads.max(1))),
+        }
+    }
+
+    pub fn replace(&self, local: Arc<LocalFiles>) {
+        *self.local.write().unwrap() = local;
+    }
+}
+
+/// 本次同步可以下载的其他客户端及其是否可用
+///
+/// 服务器列出的客户端可能已经退出，连接失败的客户端在本次同步中不再尝试
+#[derive(Debug, Default)]
+pub struct Peers {
+    list: Vec<(Url, AtomicBool)>,
+}
+
+impl Peers {
+    pub fn new(list: Vec<Url>) -> Self {
+        Self {
+            list: list
+                .into_iter()
+                .map(|a| (a, AtomicBool::new(true)))
+                .collect(),
+        }
+    }
+
+    /// 从第 `index` 个开始轮流选取最多 `count` 个可用的客户端，使下载分散到各客户端
+    pub fn candidates(&self, index: usize, count: usize) -> Vec<Url> {
+        let alive: Vec<&Url> = self
+            .list
+            .iter()
+            .filter(|a| a.1.load(Ordering::Relaxed))
+            .map(|a| &a.0)
+            .collect();
+        let count = count.min(alive.len());
+        alive
+            .into_iter()
+            .cycle()
+            .skip(index)
+            .take(count)
+            .cloned()
+            .collect()
+    }
+
+    pub fn failed(&self, peer: &Url) {
+        if let Some(a) = self.list.iter().find(|a| &a.0 == peer) {
+            if a.1.swap(false, Ordering::Relaxed) {
+                println!("Peer {peer} unreachable, skipped for this sync");
+            }
+        }
+    }
+}
+
+/// 以与服务器相同的 `/content` 接口提供本地已校验的文件，并回复 UDP 广播
+pub async fn serve(port: u16, client_id: Uuid, share: Arc<PeerShare>) -> anyhow::Result<()> {
+    let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+    tokio::spawn(async move {
+        if let Err(e) = answer_discovery(addr, port, client_id).await {
+            println!("Peer discovery stopped {e:?}");
+        }
+    });
+
+    let route = warp::get()
+        .and(warp::path("content"))
+        .and(warp::path::tail())
+        .and_then(move |tail| get_content(share.clone(), tail));
+    let (addr, server) = warp::serve(route).try_bind_ephemeral(addr)?;
+    println!("Peer sharing on {addr}");
+    server.await;
+    Ok(())
+}
+
+async fn get_content(share: Arc<PeerShare>, tail: Tail) -> Result<Response<Body>, Rejection> {
+    let rel = percent_decode_str(tail.as_str())
+        .decode_utf8()
+        .map_err(|_| warp::reject::not_found())?;
+    if rel
+        .split('/')
+        .any(|seg| seg.is_empty() || seg.starts_with("..") || seg.contains(['\\', ':']))
+    {
+        return Err(warp::reject::not_found());
+    }
+
+    // 只提供哈希已校验且之后未被修改的文件，下载方仍会校验哈希
+    let local = share.local.read().unwrap().clone();
+    let (path, hash) = local
+        .verified_file(&rel)
+        .ok_or_else(warp::reject::not_found)?;
+    let Ok(permit) = share.uploads.clone().try_acquire_owned() else {
+        return Ok(Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Body::empty())
+            .unwrap());
+    };
+
+    let file = tokio::fs::File::open(&path)
+        .await
+        .map_err(|_| warp::reject::not_found())?;
+    let len = file
+        .metadata()
+        .await
+        .map_err(|_| warp::reject::not_found())?
+        .len();
+    let hash: String = hash.iter().map(|b| format!("{b:02x}")).collect();
+    println!("Peer upload {rel}");
+
+    let body = ReaderStream::new(file).map(move |chunk| {
+        let _permit = &permit;
+        chunk
+    });
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/octet-stream")
+        .header(CONTENT_LENGTH, len)
+        .header(HASH_HEADER, hash)
+        .body(Body::wrap_stream(body))
+        .unwrap())
+}
+
+async fn answer_discovery(addr: SocketAddr, port: u16, client_id: Uuid) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind(addr).await?;
+    let reply = format!("{DISCOVER_REPLY} {client_id} {port}");
+    let mut buf = [0u8; 64];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        if &buf[..len] != DISCOVER_REQUEST {
+            continue;
+        }
+        if let Err(e) = socket.send_to(reply.as_bytes(), from).await {
+            println!("Reply discovery to {from} failed {e:?}");
+        }
+    }
+}
+
+/// 在局域网中广播，返回回复的其他客户端
+pub async fn discover(port: u16, client_id: Uuid) -> anyhow::Result<Vec<SocketAddr>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+    socket
+        .send_to(DISCOVER_REQUEST, (Ipv4Addr::BROADCAST, port))
+        .await?;
+
+    let client_id = client_id.to_string();
+    let deadline = tokio::time::Instant::now() + DISCOVER_TIMEOUT;
+    let mut peers = vec![];
+    let mut buf = [0u8; 128];
+    while let Ok(res) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, from) = res?;
+        let Ok(reply) = std::str::from_utf8(&buf[..len]) else {
+            continue;
+        };
+        let mut parts = reply.split(' ');
+        if parts.next() != Some(DISCOVER_REPLY) || parts.next() == Some(client_id.as_str()) {
+            continue;
+        }
+        if let Some(port) = parts.next().and_then(|a| a.parse().ok()) {
+            peers.push(SocketAddr::new(from.ip(), port));
+        }
+    }
+    Ok(peers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peers(count: usize) -> Peers {
+        Peers::new(
+            (0..count)
+                .map(|i| Url::parse(&format!("http://10.0.0.{i}:16343/")).unwrap())
+                .collect(),
+        )
+    }
+
+    fn hosts(list: Vec<Url>) -> Vec<String> {
+        list.iter()
+            .map(|a| a.host_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn candidates_rotate_by_index() {
+        let peers = peers(3);
+        assert_eq!(hosts(peers.candidates(0, 2)), ["10.0.0.0", "10.0.0.1"]);
+        assert_eq!(hosts(peers.candidates(2, 2)), ["10.0.0.2", "10.0.0.0"]);
+        assert_eq!(hosts(peers.candidates(4, 5)).len(), 3);
+    }
+
+    #[test]
+    fn failed_peers_are_skipped() {
+        let peers = peers(3);
+        peers.failed(&Url::parse("http://10.0.0.1:16343/").unwrap());
+        for index in 0..6 {
+            assert!(!hosts(peers.candidates(index, 2)).contains(&"10.0.0.1".to_string()));
+        }
+        assert_eq!(hosts(peers.candidates(1, 2)), ["10.0.0.2", "10.0.0.0"]);
+    }
+
+    #[test]
+    fn no_candidates_when_all_failed() {
+        let peers = peers(2);
+        peers.failed(&Url::parse("http://10.0.0.0:16343/").unwrap());
+        peers.failed(&Url::parse("http://10.0.0.1:16343/").unwrap());
+        assert!(peers.candidates(0, 2).is_empty());
+        assert!(Peers::default().candidates(3, 2).is_empty());
+    }
+}
@@ -0,0 +1,192 @@
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+/// 用户自己放在 `cwd` 中的文件，无论清单如何都不写入、不改名、不删除
+///
+/// 匹配相对 `cwd` 的路径，匹配某个文件夹时其中的文件都受保护
+#[derive(Debug)]
+pub struct Protected {
+    set: GlobSet,
+}
+
+impl Protected {
+    pub fn new(patterns: &[String]) -> anyhow::Result<Self> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            builder.add(
+                GlobBuilder::new(pattern.trim_end_matches('/'))
+                    .literal_separator(true)
+                    .build()?,
+            );
+        }
+        Ok(Self {
+            set: builder.build()?,
+        })
+    }
+
+    pub fn contains(&self, rel: &str) -> bool {
+        if self.set.is_empty() {
+            return false;
+        }
+        let mut end = rel.len();
+        loop {
+            if self.set.is_match(&rel[..end]) {
+                return true;
+            }
+            match rel[..end].rfind('/') {
+                Some(pos) => end = pos,
+                None => return false,
+            }
+        }
+    }
+}
+
+/// 检查并规范化清单中的路径，去掉空段与 `.` 段
+///
+/// 拒绝 `..`、绝对路径、盘符和反斜杠，保证路径只能指向 `cwd` 之内，
+/// 且与受保护路径的匹配和实际写入的位置一致
+pub fn normalize_manifest_path(path: &str) -> anyhow::Result<String> {
+    if path.starts_with('/') {
+        anyhow::bail!("清单中的路径 {path:?} 是绝对路径");
+    }
+    let mut segs = vec![];
+    for seg in path.split('/') {
+        match seg {
+            "" | "." => {}
+            ".." => anyhow::bail!("清单中的路径 {path:?} 包含 .."),
+            seg if seg.contains(['\\', ':', '\0']) => {
+                anyhow::bail!("清单中的路径 {path:?} 包含反斜杠或盘符")
+            }
+            seg => segs.push(seg),
+        }
+    }
+    if segs.is_empty() {
+        anyhow::bail!("清单中的路径 {path:?} 为空");
+    }
+    Ok(segs.join("/"))
+}
+
+/// 设置界面中以 `;` 分隔的 glob
+pub fn parse_protected(text: &str) -> anyhow::Result<Vec<String>> {
+    let patterns: Vec<String> = text
+        .split(';')
+        .map(|a| a.trim().replace('\\', "/"))
+        .filter(|a| !a.is_empty())
+        .collect();
+    Protected::new(&patterns)?;
+    Ok(patterns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn protected(patterns: &[&str]) -> Protected {
+        let patterns: Vec<String> = patterns.iter().map(|a| a.to_string()).collect();
+        Protected::new(&patterns).unwrap()
+    }
+
+    #[test]
+    fn empty_set_protects_nothing() {
+        let set = protected(&[]);
+        assert!(!set.contains("a.txt"));
+        assert!(!set.contains(""));
+    }
+
+    #[test]
+    fn star_does_not_cross_directories() {
+        let set = protected(&["*.cfg"]);
+        assert!(set.contains("a.cfg"));
+        assert!(!set.contains("sub/a.cfg"));
+
+        let set = protected(&["**/*.cfg"]);
+        assert!(set.contains("a.cfg"));
+        assert!(set.contains("sub/deep/a.cfg"));
+    }
+
+    #[test]
+    fn directory_protects_its_files() {
+        for pattern in ["mods", "mods/", "mods/**"] {
+            let set = protected(&[pattern]);
+            assert!(set.contains("mods/a.jar"), "{pattern}");
+            assert!(set.contains("mods/sub/b.jar"), "{pattern}");
+            assert!(!set.contains("mods2/a.jar"), "{pattern}");
+            assert!(!set.contains("other/mods"), "{pattern}");
+        }
+        let set = protected(&["saves/*"]);
+        assert!(set.contains("saves/world/level.dat"));
+        assert!(!set.contains("saves"));
+    }
+
+    #[test]
+    fn classes_and_alternatives() {
+        let set = protected(&["config/{a,b}.ini", "log[0-9].txt", "?.tmp"]);
+        assert!(set.contains("config/a.ini"));
+        assert!(set.contains("config/b.ini"));
+        assert!(!set.contains("config/c.ini"));
+        assert!(set.contains("log7.txt"));
+        assert!(!set.contains("logx.txt"));
+        assert!(set.contains("x.tmp"));
+        assert!(!set.contains("xy.tmp"));
+    }
+
+    #[test]
+    fn manifest_path_normalized() {
+        assert_eq!(normalize_manifest_path("a/b.txt").unwrap(), "a/b.txt");
+        assert_eq!(normalize_manifest_path("./a//b/./c").unwrap(), "a/b/c");
+        assert_eq!(
+            normalize_manifest_path("mods/**/*.jar").unwrap(),
+            "mods/**/*.jar"
+        );
+    }
+
+    #[test]
+    fn manifest_path_rejects_parent() {
+        assert!(normalize_manifest_path("x/../saves/a.sav").is_err());
+        assert!(normalize_manifest_path("../../outside").is_err());
+        assert!(normalize_manifest_path("a/..").is_err());
+    }
+
+    #[test]
+    fn manifest_path_rejects_absolute() {
+        assert!(normalize_manifest_path("/etc/passwd").is_err());
+        assert!(normalize_manifest_path("//server/share/a").is_err());
+    }
+
+    #[test]
+    fn manifest_path_rejects_drive() {
+        assert!(normalize_manifest_path("C:/Windows/a.dll").is_err());
+        assert!(normalize_manifest_path("C:a.dll").is_err());
+        assert!(normalize_manifest_path("a/b.txt:stream").is_err());
+    }
+
+    #[test]
+    fn manifest_path_rejects_backslash() {
+        assert!(normalize_manifest_path("x\\..\\saves\\a.sav").is_err());
+        assert!(normalize_manifest_path("\\\\server\\share").is_err());
+        assert!(normalize_manifest_path("a/b\\c").is_err());
+    }
+
+    #[test]
+    fn manifest_path_rejects_empty() {
+        assert!(normalize_manifest_path("").is_err());
+        assert!(normalize_manifest_path("./").is_err());
+    }
+
+    #[test]
+    fn normalized_path_cannot_bypass_protection() {
+        let set = protected(&["saves"]);
+        assert!(normalize_manifest_path("x/../saves/a.sav").is_err());
+        assert!(set.contains(&normalize_manifest_path("./saves//a.sav").unwrap()));
+    }
+
+    #[test]
+    fn parse_separated_patterns() {
+        assert_eq!(
+            parse_protected(" saves\\** ; ;mods/ ;").unwrap(),
+            vec!["saves/**", "mods/"]
+        );
+        assert!(parse_protected("").unwrap().is_empty());
+        assert!(parse_protected("a[").is_err());
+        assert!(parse_protected("{a,b").is_err());
+    }
+}
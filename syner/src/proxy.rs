@@ -0,0 +1,80 @@
+use http_client::isahc::IsahcClient;
+use isahc::config::Configurable;
+use url::Url;
+
+use crate::client_model::Config;
+
+/// 代理地址支持的协议
+const SCHEMES: [&str; 6] = ["http", "https", "socks4", "socks4a", "socks5", "socks5h"];
+
+/// 检查设置界面中填写的代理地址，空字符串表示不设置
+pub fn check_proxy(proxy: &str) -> anyhow::Result<()> {
+    if proxy.is_empty() {
+        return Ok(());
+    }
+    let url = Url::parse(proxy)?;
+    if !SCHEMES.contains(&url.scheme()) {
+        anyhow::bail!("不支持的协议 {}", url.scheme());
+    }
+    if url.host_str().is_none() {
+        anyhow::bail!("缺少主机");
+    }
+    Ok(())
+}
+
+/// 配置中的代理优先，未配置时按服务器协议读取 `HTTPS_PROXY` / `HTTP_PROXY` / `ALL_PROXY`
+fn proxy_url(config: &Config) -> Option<String> {
+    if !config.proxy.url.is_empty() {
+        return Some(config.proxy.url.clone());
+    }
+    let vars: &[&str] = match config.server.scheme() {
+        "https" => &["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"],
+        _ => &["HTTP_PROXY", "http_proxy", "ALL_PROXY", "all_proxy"],
+    };
+    vars.iter()
+        .filter_map(|a| std::env::var(a).ok())
+        .find(|a| !a.trim().is_empty())
+}
+
+/// 配置中的列表优先，未配置时读取 `NO_PROXY`
+fn no_proxy(config: &Config) -> Vec<String> {
+    if !config.proxy.no_proxy.is_empty() {
+        return config.proxy.no_proxy.clone();
+    }
+    ["NO_PROXY", "no_proxy"]
+        .iter()
+        .filter_map(|a| std::env::var(a).ok())
+        .find(|a| !a.trim().is_empty())
+        .map(|a| {
+            a.split(',')
+                .map(|a| a.trim().to_string())
+                .filter(|a| !a.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// 访问服务器使用的 HTTP 客户端，代理地址中可以带用户名和密码
+pub fn http_client(config: &Config) -> anyhow::Result<surf::Client> {
+    let mut builder = isahc::HttpClient::builder();
+    if let Some(proxy) = proxy_url(config) {
+        check_proxy(&proxy)?;
+        println!("Use proxy {}", redact(&proxy));
+        builder = builder
+            .proxy(Some(proxy.parse::<isahc::http::Uri>()?))
+            .proxy_blacklist(no_proxy(config));
+    }
+    let client = IsahcClient::from_client(builder.build()?);
+    Ok(surf::Client::with_http_client(client))
+}
+
+/// 日志中隐藏代理的密码
+fn redact(proxy: &str) -> String {
+    match Url::parse(proxy) {
+        Ok(mut url) if url.password().is_some() => {
+            let _ = url.set_password(Some("***"));
+            url.to_string()
+        }
+        _ => proxy.to_string(),
+    }
+}
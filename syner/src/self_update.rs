@@ -0,0 +1,133 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::anyhow;
+use futures_lite::AsyncReadExt;
+use model::{calc_hash, ClientUpdate};
+use sha3::{Digest, Sha3_256};
+use tokio::io::AsyncWriteExt;
+
+use crate::client_model::Config;
+use crate::send_request;
+use crate::servers::Servers;
+
+/// 已替换的程序路径，退出时需要启动新的程序。替换后 `current_exe` 在部分系统上
+/// 会指向改名后的旧程序，所以在替换前记录
+static UPDATED: OnceLock<PathBuf> = OnceLock::new();
+
+/// 在程序路径后追加后缀，如 `syner.exe.new`
+fn with_suffix(exe: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(exe.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// 删除上次更新留下的旧程序，运行中的程序无法删除，只能在新程序启动后清理
+pub fn cleanup() {
+    let Ok(exe) = std::env::current_exe() else {
+        return;
+    };
+    let old = with_suffix(&exe, ".old");
+    if old.exists() {
+        if let Err(e) = std::fs::remove_file(&old) {
+            println!("Remove {old:?} failed {e:?}");
+        }
+    }
+}
+
+/// 清单中发布的本平台程序与当前程序不同时下载、校验并替换，返回是否已替换
+///
+/// 校验用的哈希与程序来自同一台服务器，只能发现传输中的损坏，不能防止篡改：
+/// 开启自动更新等于允许所有配置的服务器和镜像在本机执行任意程序
+pub async fn check_update(
+    config: &Config,
+    servers: &Servers,
+    update: Option<ClientUpdate>,
+) -> anyhow::Result<bool> {
+    if !config.self_update.enabled {
+        return Ok(false);
+    }
+    // 服务器没有发布本平台的程序
+    let Some(update) = update else {
+        return Ok(false);
+    };
+    let server = servers.active();
+    let client = servers.client();
+
+    let exe = std::env::current_exe()?;
+    let current = calc_hash(tokio::fs::File::open(&exe).await?).await?;
+    if current == update.hash.as_slice() {
+        return Ok(false);
+    }
+
+    let hash: String = update.hash.iter().map(|b| format!("{b:02x}")).collect();
+    println!(
+        "Download client update {hash} ({} bytes) from {server}",
+        update.len
+    );
+    let res = send_request(config, || {
+        Ok(client.get(server.join(&format!("blob/{hash}"))?))
+    })
+    .await?;
+
+    // 先写入 .new，再把当前程序改名为 .old 后换上新程序，失败时恢复
+    let new = with_suffix(&exe, ".new");
+    let old = with_suffix(&exe, ".old");
+    if let Err(e) = download(res, &new, &update).await {
+        let _ = tokio::fs::remove_file(&new).await;
+        return Err(e);
+    }
+    tokio::fs::set_permissions(&new, tokio::fs::metadata(&exe).await?.permissions()).await?;
+    let _ = tokio::fs::remove_file(&old).await;
+    tokio::fs::rename(&exe, &old).await?;
+    if let Err(e) = tokio::fs::rename(&new, &exe).await {
+        tokio::fs::rename(&old, &exe).await?;
+        return Err(e.into());
+    }
+    println!("Client updated, restart required");
+    let _ = UPDATED.set(exe);
+    Ok(true)
+}
+
+/// 边下载边写入 `dst` 并计算哈希，长度或哈希与清单不符时返回错误
+async fn download(
+    mut res: surf::Response,
+    dst: &Path,
+    update: &ClientUpdate,
+) -> anyhow::Result<()> {
+    let mut body = res.take_body();
+    let mut file = tokio::fs::File::create(dst).await?;
+    let mut buffer = [0; 4096];
+    let mut size = 0;
+    let mut hasher = Sha3_256::new();
+    loop {
+        let len = body.read(&mut buffer).await?;
+        if len == 0 {
+            break;
+        }
+        size += len as u64;
+        if size > update.len {
+            return Err(anyhow!("下载的新版本校验失败"));
+        }
+        file.write_all(&buffer[..len]).await?;
+        hasher.update(&buffer[..len]);
+    }
+    file.flush().await?;
+    if size != update.len || hasher.finalize().as_slice() != update.hash.as_slice() {
+        return Err(anyhow!("下载的新版本校验失败"));
+    }
+    Ok(())
+}
+
+/// 程序已被替换时以相同的参数启动新程序
+pub fn relaunch_if_updated() -> anyhow::Result<()> {
+    let Some(exe) = UPDATED.get() else {
+        return Ok(());
+    };
+    println!("Relaunch {exe:?}");
+    std::process::Command::new(exe)
+        .args(std::env::args_os().skip(1))
+        .spawn()?;
+    Ok(())
+}
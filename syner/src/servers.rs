@@ -0,0 +1,93 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use url::Url;
+
+use crate::client_model::Config;
+
+/// 按配置顺序排列的服务器及其是否可用
+#[derive(Debug)]
+pub struct Servers {
+    list: Vec<(Url, AtomicBool)>,
+    /// 当前使用的服务器
+    active: AtomicUsize,
+    /// 按代理设置创建的客户端
+    client: surf::Client,
+}
+
+impl Servers {
+    pub fn new(config: &Config, client: surf::Client) -> Self {
+        let mut list: Vec<Url> = vec![config.server.clone()];
+        for mirror in &config.mirrors {
+            if !list.contains(mirror) {
+                list.push(mirror.clone());
+            }
+        }
+        Self {
+            list: list
+                .into_iter()
+                .map(|a| (a, AtomicBool::new(true)))
+                .collect(),
+            active: AtomicUsize::new(0),
+            client,
+        }
+    }
+
+    pub fn client(&self) -> &surf::Client {
+        &self.client
+    }
+
+    pub fn active(&self) -> Url {
+        self.list[self.active.load(Ordering::Relaxed)].0.clone()
+    }
+
+    /// 全部服务器，按配置顺序
+    pub fn all(&self) -> Vec<Url> {
+        self.list.iter().map(|a| a.0.clone()).collect()
+    }
+
+    /// 当前服务器优先，其后是其余可用的服务器，最后是失败过的服务器
+    pub fn candidates(&self) -> Vec<Url> {
+        let active = self.active.load(Ordering::Relaxed);
+        let mut list: Vec<(bool, usize, Url)> = self
+            .list
+            .iter()
+            .enumerate()
+            .map(|(i, (url, healthy))| (!healthy.load(Ordering::Relaxed), i, url.clone()))
+            .collect();
+        list.sort_by_key(|a| (a.1 != active, a.0, a.1));
+        list.into_iter().map(|a| a.2).collect()
+    }
+
+    /// 请求成功，当前服务器已失败时改用该服务器，返回当前服务器是否改变
+    pub fn succeeded(&self, server: &Url) -> bool {
+        let Some(index) = self.position(server) else {
+            return false;
+        };
+        self.list[index].1.store(true, Ordering::Relaxed);
+        let active = self.active.load(Ordering::Relaxed);
+        if active == index || self.list[active].1.load(Ordering::Relaxed) {
+            return false;
+        }
+        self.active
+            .compare_exchange(active, index, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    pub fn failed(&self, server: &Url) {
+        if let Some(index) = self.position(server) {
+            self.list[index].1.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// 改用指定的服务器，用于选定提供清单的服务器
+    pub fn select(&self, server: &Url) {
+        if let Some(index) = self.position(server) {
+            self.list[index].1.store(true, Ordering::Relaxed);
+            self.active.store(index, Ordering::Relaxed);
+        }
+    }
+
+    fn position(&self, server: &Url) -> Option<usize> {
+        self.list.iter().position(|a| &a.0 == server)
+    }
+}
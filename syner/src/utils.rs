@@ -1,3 +1,5 @@
+use std::path::Path;
+
 #[cfg(target_os = "windows")]
 pub fn is_valid_path(path: &str) -> bool {
     let invalid_chars = ['<', '>', ':', '"', '|', '?', '*'];
@@ -13,3 +15,54 @@ pub struct SendT<T>(pub T);
 
 unsafe impl<T> Send for SendT<T> {}
 unsafe impl<T> Sync for SendT<T> {}
+
+/// 展开删除用的 glob，返回相对 `root` 的文件路径（以 `/` 分隔）
+///
+/// 只遍历 glob 中第一个通配符之前的目录
+pub fn expand_glob(root: &Path, pattern: &str) -> anyhow::Result<Vec<String>> {
+    if pattern.starts_with('/') || pattern.split('/').any(|seg| seg == "..") {
+        anyhow::bail!("invalid glob {pattern:?}");
+    }
+    let matcher = globset::GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()?
+        .compile_matcher();
+
+    let mut start = root.to_path_buf();
+    for seg in pattern
+        .split('/')
+        .take_while(|seg| !seg.contains(['*', '?', '[', '{']))
+    {
+        start.push(seg);
+    }
+
+    let mut files = vec![];
+    let mut dirs = vec![start];
+    while let Some(dir) = dirs.pop() {
+        let Ok(read_dir) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in read_dir {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let path = entry.path();
+            if file_type.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            if !file_type.is_file() {
+                continue;
+            }
+            let rel: Vec<_> = path
+                .strip_prefix(root)?
+                .iter()
+                .map(|a| a.to_string_lossy())
+                .collect();
+            let rel = rel.join("/");
+            if matcher.is_match(&rel) {
+                files.push(rel);
+            }
+        }
+    }
+    Ok(files)
+}